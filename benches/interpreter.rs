@@ -0,0 +1,104 @@
+//! Throughput of the interpreter on fixed workloads, run with `cargo bench`.
+//!
+//! Each workload is run with and without the cache of decoded instructions and
+//! reports the number of executed instructions per second.
+
+use std::time::Instant;
+
+use synacor::{BufferOutput, Cpu, QueueInput, StopReason};
+
+const ROM: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/roms/challenge.bin");
+
+// Registers as operands
+const R0: u16 = 32768;
+const R1: u16 = 32769;
+const R2: u16 = 32770;
+const R3: u16 = 32771;
+const MINUS_ONE: u16 = 32767;
+
+// Two nested countdown loops of `add`/`jt`
+#[rustfmt::skip]
+const LOOPS: &[u16] = &[
+    1, R1, 400,                 // 0: set r1 400
+    1, R0, 30000,               // 3: set r0 30000
+    9, R0, R0, MINUS_ONE,       // 6: add r0 r0 -1
+    7, R0, 6,                   // 10: jt r0 6
+    9, R1, R1, MINUS_ONE,       // 13: add r1 r1 -1
+    7, R1, 3,                   // 17: jt r1 3
+    0,                          // 20: halt
+];
+
+// A function calling itself until r0 is 0, started many times
+#[rustfmt::skip]
+const RECURSION: &[u16] = &[
+    1, R1, 500,                 // 0: set r1 500
+    1, R0, 10000,               // 3: set r0 10000
+    17, 16,                     // 6: call 16
+    9, R1, R1, MINUS_ONE,       // 8: add r1 r1 -1
+    7, R1, 3,                   // 12: jt r1 3
+    0,                          // 15: halt
+    8, R0, 25,                  // 16: jf r0 25
+    9, R0, R0, MINUS_ONE,       // 19: add r0 r0 -1
+    17, 16,                     // 23: call 16
+    18,                         // 25: ret
+];
+
+// Copy the first words of memory further away, many times
+#[rustfmt::skip]
+const MEMORY: &[u16] = &[
+    1, R3, 1000,                // 0: set r3 1000
+    1, R0, 2000,                // 3: set r0 2000
+    15, R2, R0,                 // 6: rmem r2 r0
+    9, R1, R0, 10000,           // 9: add r1 r0 10000
+    16, R1, R2,                 // 13: wmem r1 r2
+    9, R0, R0, MINUS_ONE,       // 16: add r0 r0 -1
+    7, R0, 6,                   // 20: jt r0 6
+    9, R3, R3, MINUS_ONE,       // 23: add r3 r3 -1
+    7, R3, 3,                   // 27: jt r3 3
+    0,                          // 30: halt
+];
+
+fn assemble(words: &[u16]) -> Vec<u8> {
+    words.iter().flat_map(|w| w.to_le_bytes()).collect()
+}
+
+// Run `rom` until it stops with `until` and return the number of executed
+// instructions and the number of instructions per second.
+fn measure(rom: Vec<u8>, until: StopReason, decode_cache: bool) -> (u64, f64) {
+    let mut cpu = Cpu::load(rom).expect("failed to load the ROM");
+    cpu.set_input(Box::new(QueueInput::new()));
+    cpu.set_output(Box::new(BufferOutput::new()));
+    cpu.set_decode_cache(decode_cache);
+
+    let start = Instant::now();
+    let mut steps = 0u64;
+    loop {
+        match cpu.step() {
+            StopReason::StepLimit => steps += 1,
+            reason if reason == until => break,
+            reason => panic!("unexpected stop: {reason}"),
+        }
+    }
+    (steps, steps as f64 / start.elapsed().as_secs_f64())
+}
+
+fn main() {
+    let challenge = std::fs::read(ROM).expect("failed to read the challenge ROM");
+    // The self-test runs until the game asks for the first command
+    let workloads = [
+        ("self-test", challenge, StopReason::WaitingForInput),
+        ("add/jt loops", assemble(LOOPS), StopReason::Halted),
+        ("recursion", assemble(RECURSION), StopReason::Halted),
+        ("rmem/wmem", assemble(MEMORY), StopReason::Halted),
+    ];
+
+    println!(
+        "{:<20} {:>12} {:>16} {:>16}",
+        "workload", "instructions", "no cache instr/s", "cache instr/s"
+    );
+    for (name, rom, until) in workloads {
+        let (steps, uncached) = measure(rom.clone(), until.clone(), false);
+        let (_, cached) = measure(rom, until, true);
+        println!("{name:<20} {steps:>12} {uncached:>16.0} {cached:>16.0}");
+    }
+}
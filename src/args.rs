@@ -1,10 +1,27 @@
 use std::env;
 
+use synacor::TraceFormat;
+
 #[derive(Debug)]
 pub struct Args {
-    pub breakpoint: Option<u16>,
+    pub breakpoints: Vec<u16>,
+    pub call_graph: Option<String>,
+    pub cfg: Option<(u16, String)>,
+    pub coverage: Option<String>,
     pub disassemble: bool,
     pub filename: String,
+    pub history: Option<usize>,
+    pub input: Option<String>,
+    pub profile: bool,
+    pub raw: bool,
+    pub record: Option<String>,
+    pub replay: Option<String>,
+    pub restore: Option<String>,
+    pub save: Option<String>,
+    pub symbols: Option<String>,
+    pub trace: Option<String>,
+    pub trace_format: TraceFormat,
+    pub trace_ranges: Vec<(u16, u16)>,
     pub verbose: bool,
 }
 
@@ -13,26 +30,100 @@ pub fn read_args() -> Args {
 
     let prog_name = args.next().unwrap();
 
-    let mut breakpoint = None;
+    let mut breakpoints = vec![];
+    let mut call_graph = None;
+    let mut cfg = None;
+    let mut coverage = None;
     let mut filename = None;
     let mut disassemble = false;
+    let mut history = None;
+    let mut input = None;
+    let mut profile = false;
+    let mut raw = false;
+    let mut record = None;
+    let mut replay = None;
+    let mut restore = None;
+    let mut save = None;
+    let mut symbols = None;
+    let mut trace = None;
+    let mut trace_format = TraceFormat::Text;
+    let mut trace_ranges = vec![];
     let mut verbose = false;
 
     while let Some(arg) = args.next() {
         match arg.as_str() {
             "--break" => {
-                let line: u16 = args
-                    .next()
-                    .expect("Expected a number after --break")
-                    .parse::<u16>()
-                    .expect("Failed to parse breakpoint");
-                breakpoint = Some(line);
+                let addr = args.next().expect("Expected an address after --break");
+                breakpoints.push(crate::parse_addr(&addr).expect("Failed to parse breakpoint"));
+            }
+            "--call-graph" => {
+                let fname = args.next().expect("Expected a filename after --call-graph");
+                call_graph = Some(fname);
+            }
+            "--cfg" => {
+                let addr = args.next().expect("Expected an address after --cfg");
+                let addr = crate::parse_addr(&addr).expect("Failed to parse address");
+                let fname = args.next().expect("Expected a filename after --cfg <addr>");
+                cfg = Some((addr, fname));
+            }
+            "--coverage" => {
+                let fname = args.next().expect("Expected a filename after --coverage");
+                coverage = Some(fname);
             }
             "--disassemble" => disassemble = true,
             "--help" => {
                 print_help(&prog_name);
                 std::process::exit(0);
             }
+            "--history" => {
+                let limit = args
+                    .next()
+                    .expect("Expected a number after --history")
+                    .parse::<usize>()
+                    .expect("Failed to parse history limit");
+                history = Some(limit);
+            }
+            "--input" => {
+                let fname = args.next().expect("Expected a filename after --input");
+                input = Some(fname);
+            }
+            "--profile" => profile = true,
+            "--raw" => raw = true,
+            "--record" => {
+                let fname = args.next().expect("Expected a filename after --record");
+                record = Some(fname);
+            }
+            "--replay" => {
+                let fname = args.next().expect("Expected a filename after --replay");
+                replay = Some(fname);
+            }
+            "--restore" => {
+                let fname = args.next().expect("Expected a filename after --restore");
+                restore = Some(fname);
+            }
+            "--save" => {
+                let fname = args.next().expect("Expected a filename after --save");
+                save = Some(fname);
+            }
+            "--symbols" => {
+                let fname = args.next().expect("Expected a filename after --symbols");
+                symbols = Some(fname);
+            }
+            "--trace" => {
+                let fname = args.next().expect("Expected a filename after --trace");
+                trace = Some(fname);
+            }
+            "--trace-format" => {
+                trace_format = match args.next().as_deref() {
+                    Some("text") => TraceFormat::Text,
+                    Some("json") => TraceFormat::Json,
+                    _ => panic!("Expected text or json after --trace-format"),
+                };
+            }
+            "--trace-range" => {
+                let range = args.next().expect("Expected a range after --trace-range");
+                trace_ranges.push(crate::parse_range(&range).expect("Failed to parse range"));
+            }
             "--verbose" => verbose = true,
             _ => {
                 if filename.is_some() {
@@ -47,9 +138,24 @@ pub fn read_args() -> Args {
 
     if let Some(fname) = filename {
         Args {
-            breakpoint,
+            breakpoints,
+            call_graph,
+            cfg,
+            coverage,
             disassemble,
             filename: fname,
+            history,
+            input,
+            profile,
+            raw,
+            record,
+            replay,
+            restore,
+            save,
+            symbols,
+            trace,
+            trace_format,
+            trace_ranges,
             verbose,
         }
     } else {
@@ -60,11 +166,39 @@ pub fn read_args() -> Args {
 }
 
 fn print_help(name: &str) {
-    println!("Usage: {name} [--break line] [--disassemble] <filename>");
+    println!("Usage: {name} [--break addr]... [--call-graph file] [--cfg addr file]");
+    println!("       [--coverage file] [--disassemble] [--history n] [--input file] [--profile]");
+    println!("       [--raw] [--record file] [--replay file] [--restore file] [--save file]");
+    println!("       [--symbols file] [--trace file] [--trace-format text|json]");
+    println!("       [--trace-range start..end]... [--verbose]");
+    println!("       <filename>");
     println!();
     println!("Options:");
-    println!("  --break <line>    Set a breakpoint at the given line number");
+    println!(
+        "  --break <addr>    Set a breakpoint at the address, decimal or hexadecimal with 0x,"
+    );
+    println!("                    can be repeated");
+    println!("  --call-graph <file>");
+    println!("                    Write the call graph of the program to a Graphviz DOT file");
+    println!("  --cfg <addr> <file>");
+    println!("                    Write the control-flow graph of the function at <addr> to a");
+    println!("                    Graphviz DOT file, <addr> is decimal or hexadecimal with 0x");
+    println!("  --coverage <file> Save the memory accesses of the program when quitting");
     println!("  --disassemble     Print disassemble code from <filename> to stdout");
-    println!("  --verbose         Print debug message like the executed opcodes");
+    println!("  --history <n>     Instructions kept for reverse execution, 0 disables it");
+    println!("  --input <file>    Read the program input from a file before reading stdin");
+    println!("  --profile         Count the executed instructions per address and function");
+    println!("  --raw             Disassemble with opcodes and operands in hexadecimal");
+    println!("  --record <file>   Record the input and output of the program, saved when quitting");
+    println!("  --replay <file>   Replay a recorded session and stop where the program diverges");
+    println!("  --restore <file>  Restore the machine from a snapshot before starting");
+    println!("  --save <file>     Save a snapshot of the machine when quitting");
+    println!("  --symbols <file>  Load symbol names, one '<addr> <name>' per line");
+    println!("  --trace <file>    Write a record for each executed instruction to a file");
+    println!("  --trace-format <text|json>");
+    println!("                    Format of the trace, text by default");
+    println!("  --trace-range <start..end>");
+    println!("                    Only trace instructions in the range, can be repeated");
+    println!("  --verbose         Trace the executed instructions to stdout");
     println!("  -h, --help        Print this help message");
 }
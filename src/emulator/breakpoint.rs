@@ -0,0 +1,126 @@
+use crate::emulator::expr::{Env, Expr};
+use crate::emulator::{VmError, layout};
+use std::fmt;
+
+#[derive(Debug, Clone)]
+pub struct Breakpoint {
+    pub id: usize,
+    pub addr: u16,
+    pub name: Option<String>,
+    pub enabled: bool,
+    pub hits: u64, // number of times it was reached with its condition true
+    pub condition: Option<Expr>, // only stop if the condition is true
+    pub ignore: u64, // number of the next hits that don't stop the execution
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "#{:<3} {:05} (0x{:04x}) {:<8} hits: {}",
+            self.id,
+            self.addr,
+            self.addr,
+            if self.enabled { "enabled" } else { "disabled" },
+            self.hits
+        )?;
+        if let Some(name) = &self.name {
+            write!(f, " <{name}>")?;
+        }
+        if let Some(cond) = &self.condition {
+            write!(f, " if {cond}")?;
+        }
+        if self.ignore > 0 {
+            write!(f, " (ignore next {})", self.ignore)?;
+        }
+        Ok(())
+    }
+}
+
+/// Table of breakpoints. Each breakpoint gets a unique id that is never
+/// reused, even after it has been deleted.
+#[derive(Debug, Default)]
+pub struct Breakpoints {
+    pub(crate) list: Vec<Breakpoint>,
+    pub(crate) next_id: usize,
+}
+
+impl Breakpoints {
+    /// Add a breakpoint at `addr` and return its id.
+    pub fn add(&mut self, addr: u16, name: Option<&str>) -> Result<usize, VmError> {
+        if !layout::is_mem(addr) {
+            return Err(VmError::InvalidAddress(addr));
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.list.push(Breakpoint {
+            id,
+            addr,
+            name: name.map(str::to_string),
+            enabled: true,
+            hits: 0,
+            condition: None,
+            ignore: 0,
+        });
+        Ok(id)
+    }
+
+    /// Remove the breakpoint `id` and return it.
+    pub fn delete(&mut self, id: usize) -> Option<Breakpoint> {
+        let idx = self.list.iter().position(|bp| bp.id == id)?;
+        Some(self.list.remove(idx))
+    }
+
+    pub fn get_mut(&mut self, id: usize) -> Option<&mut Breakpoint> {
+        self.list.iter_mut().find(|bp| bp.id == id)
+    }
+
+    /// Iterate over breakpoints in the order they were added.
+    pub fn iter(&self) -> impl Iterator<Item = &Breakpoint> {
+        self.list.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    // Returns true if an enabled breakpoint is set at the current IP and its
+    // condition is true. Unlike `hit` nothing is updated.
+    pub(crate) fn matches(&self, env: &Env) -> bool {
+        self.list.iter().any(|bp| {
+            bp.enabled
+                && bp.addr == env.ip
+                && bp
+                    .condition
+                    .as_ref()
+                    .is_none_or(|expr| expr.is_true(env).unwrap_or(true))
+        })
+    }
+
+    // Returns true if an enabled breakpoint set at the current IP stops the
+    // execution. Breakpoints with a condition that can't be evaluated stop it
+    // so the problem can be looked at.
+    pub(crate) fn hit(&mut self, env: &Env) -> bool {
+        let mut hit = false;
+        for bp in self
+            .list
+            .iter_mut()
+            .filter(|bp| bp.enabled && bp.addr == env.ip)
+        {
+            let cond = match &bp.condition {
+                Some(expr) => expr.is_true(env).unwrap_or(true),
+                None => true,
+            };
+            if !cond {
+                continue;
+            }
+            bp.hits += 1;
+            if bp.ignore > 0 {
+                bp.ignore -= 1;
+            } else {
+                hit = true;
+            }
+        }
+        hit
+    }
+}
@@ -0,0 +1,125 @@
+use crate::emulator::history::{Change, History};
+use std::fmt;
+
+/// A function call tracked by the shadow call stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    pub call_site: u16, // address of the `call` instruction
+    pub target: u16,    // address of the called function
+    pub ret: u16,       // return address pushed on the stack
+    pub sp: usize,      // stack depth once the return address is pushed
+}
+
+/// A `ret` that didn't return to the caller of the current function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mismatch {
+    pub ip: u16,               // address of the `ret` instruction
+    pub expected: Option<u16>, // return address of the current frame, if any
+    pub actual: u16,           // address popped from the stack
+    pub unwound: usize,        // number of frames dropped to find the target
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "ret at {:05} (0x{:04x}) returned to 0x{:04x}",
+            self.ip, self.ip, self.actual
+        )?;
+        match self.expected {
+            Some(expected) => write!(f, " instead of 0x{expected:04x}")?,
+            None => write!(f, " outside of any call")?,
+        }
+        if self.unwound > 0 {
+            write!(f, ", {} frame(s) unwound", self.unwound)?;
+        }
+        Ok(())
+    }
+}
+
+/// Shadow call stack maintained by `call` and `ret`.
+///
+/// The stack of the CPU mixes return addresses and pushed data, this keeps
+/// only the calls so the debugger knows how the current IP was reached.
+#[derive(Debug, Default, Clone)]
+pub struct CallStack {
+    frames: Vec<Frame>,
+    pub mismatches: u64,
+    pub last_mismatch: Option<Mismatch>,
+}
+
+impl CallStack {
+    /// Frames from the outermost to the innermost call.
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.frames.clear();
+        self.mismatches = 0;
+        self.last_mismatch = None;
+    }
+
+    // Changes done by the CPU are recorded in `history` so they can be reverted.
+    pub(crate) fn on_call(&mut self, frame: Frame, history: &mut History) {
+        self.frames.push(frame);
+        history.record(Change::FramePush);
+    }
+
+    // Called when a value is popped from the stack. If the return address of a
+    // frame is popped the function will never return, so its frame is dropped.
+    pub(crate) fn on_pop(&mut self, sp: usize, history: &mut History) {
+        while self.frames.last().is_some_and(|frame| frame.sp > sp) {
+            self.pop_frame(history);
+        }
+    }
+
+    // Called when the `ret` at `ip` jumps to `addr`, `sp` is the depth of the
+    // stack before popping `addr`.
+    pub(crate) fn on_ret(&mut self, ip: u16, addr: u16, sp: usize, history: &mut History) {
+        self.on_pop(sp, history);
+        let expected = self.frames.last().map(|frame| frame.ret);
+        if expected == Some(addr) {
+            self.pop_frame(history);
+            return;
+        }
+
+        // The program may drop frames by popping their return address, so if a
+        // caller expects this address the frames above it are discarded.
+        let mut unwound = 0;
+        if let Some(idx) = self.frames.iter().rposition(|frame| frame.ret == addr) {
+            while self.frames.len() > idx {
+                self.pop_frame(history);
+                unwound += 1;
+            }
+            unwound -= 1; // the frame of the caller is not unwound, it returns
+        }
+        history.record(Change::Mismatch(self.mismatches, self.last_mismatch));
+        self.mismatches += 1;
+        self.last_mismatch = Some(Mismatch {
+            ip,
+            expected,
+            actual: addr,
+            unwound,
+        });
+    }
+
+    fn pop_frame(&mut self, history: &mut History) {
+        if let Some(frame) = self.frames.pop() {
+            history.record(Change::FramePop(frame));
+        }
+    }
+
+    // Used to restore frames without recording anything.
+    pub(crate) fn push(&mut self, frame: Frame) {
+        self.frames.push(frame);
+    }
+
+    pub(crate) fn pop(&mut self) {
+        self.frames.pop();
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Write};
+
+use crate::emulator::disasm::loaded_target;
+use crate::emulator::{Disassembly, Insn, insn, layout};
+
+/// Straight sequence of instructions, only the last one can change the flow.
+#[derive(Debug, Clone)]
+pub struct BasicBlock {
+    pub start: u16,
+    pub insns: Vec<(u16, Insn)>,
+    pub succs: Vec<(u16, Edge)>,
+}
+
+/// Why the execution goes from a block to another one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Edge {
+    /// The next instruction, including the return of a `call`.
+    Next,
+    Jump,
+    /// Taken when the condition of `jt` is true, or the one of `jf` is false.
+    Branch(bool),
+}
+
+/// Control-flow graph of the code reachable from a function entry. A `call`
+/// ends a block and continues with the next instruction, the called function
+/// is not part of the graph.
+#[derive(Debug, Clone)]
+pub struct Cfg {
+    pub entry: u16,
+    pub blocks: BTreeMap<u16, BasicBlock>,
+    /// Targets of the `call` instructions, literal or set in the register just
+    /// before the call.
+    pub calls: BTreeSet<u16>,
+}
+
+impl Cfg {
+    pub fn new(mem: &[u16], entry: u16) -> Cfg {
+        // Find the instructions and the addresses that start a block
+        let mut insns = BTreeMap::new();
+        let mut leaders = BTreeSet::from([entry]);
+        let mut calls = BTreeSet::new();
+        let mut todo = vec![entry];
+        while let Some(addr) = todo.pop() {
+            if insns.contains_key(&addr) {
+                continue;
+            }
+            let Ok((insn, next)) = insn::decode(mem, addr) else {
+                continue;
+            };
+            insns.insert(addr, (insn, next));
+            let succs = succs(&insn, next);
+            if ends_block(&insn) {
+                leaders.extend(succs.iter().map(|(addr, _)| *addr));
+            }
+            if let Insn::Call(target) = insn
+                && layout::is_mem(target)
+            {
+                calls.insert(target);
+            }
+            if let Ok((following @ Insn::Call(_), _)) = insn::decode(mem, next)
+                && let Some(target) = loaded_target(&insn, &following)
+            {
+                calls.insert(target);
+            }
+            todo.extend(succs.iter().map(|(addr, _)| *addr));
+        }
+
+        let mut blocks = BTreeMap::new();
+        for &start in leaders.iter().filter(|addr| insns.contains_key(addr)) {
+            let mut block = BasicBlock {
+                start,
+                insns: vec![],
+                succs: vec![],
+            };
+            let mut addr = start;
+            while let Some(&(insn, next)) = insns.get(&addr) {
+                block.insns.push((addr, insn));
+                if ends_block(&insn) {
+                    block.succs = succs(&insn, next);
+                    break;
+                }
+                if leaders.contains(&next) {
+                    block.succs = vec![(next, Edge::Next)];
+                    break;
+                }
+                addr = next;
+            }
+            blocks.insert(start, block);
+        }
+
+        Cfg {
+            entry,
+            blocks,
+            calls,
+        }
+    }
+
+    /// Write the graph in the Graphviz DOT format.
+    pub fn write_dot(&self, out: &mut impl Write) -> io::Result<()> {
+        let label = |addr: u16| {
+            if self.calls.contains(&addr) || addr == self.entry {
+                Some(format!("fn_{addr:04x}"))
+            } else if self.blocks.contains_key(&addr) {
+                Some(format!("loc_{addr:04x}"))
+            } else {
+                None
+            }
+        };
+
+        writeln!(out, "digraph fn_{:04x} {{", self.entry)?;
+        writeln!(out, "    node [shape=box, fontname=\"monospace\"];")?;
+        for block in self.blocks.values() {
+            let mut text = format!("{}:\\l", label(block.start).unwrap_or_default());
+            for (addr, insn) in &block.insns {
+                let insn = insn.with_labels(label);
+                text.push_str(&format!("{addr:04x}  {}\\l", escape(&insn)));
+            }
+            writeln!(out, "    b_{:04x} [label=\"{text}\"];", block.start)?;
+        }
+        for block in self.blocks.values() {
+            for (succ, edge) in &block.succs {
+                if !self.blocks.contains_key(succ) {
+                    continue;
+                }
+                let attrs = match edge {
+                    Edge::Next => "",
+                    Edge::Jump => " [style=bold]",
+                    Edge::Branch(true) => " [label=\"true\", color=darkgreen]",
+                    Edge::Branch(false) => " [label=\"false\", color=red]",
+                };
+                writeln!(out, "    b_{:04x} -> b_{succ:04x}{attrs};", block.start)?;
+            }
+        }
+        writeln!(out, "}}")
+    }
+}
+
+/// Write the graph of the calls between the functions, in the Graphviz DOT
+/// format. Functions are the program entry at address 0, the functions found
+/// by `disasm` and the functions they call.
+pub fn write_call_graph(mem: &[u16], disasm: &Disassembly, out: &mut impl Write) -> io::Result<()> {
+    let mut todo = vec![layout::MEM_MIN];
+    todo.extend(disasm.functions());
+    let mut calls = BTreeMap::new();
+    while let Some(f) = todo.pop() {
+        if calls.contains_key(&f) {
+            continue;
+        }
+        let callees = Cfg::new(mem, f).calls;
+        todo.extend(callees.iter().copied());
+        calls.insert(f, callees);
+    }
+
+    writeln!(out, "digraph calls {{")?;
+    writeln!(out, "    node [shape=box, fontname=\"monospace\"];")?;
+    for f in calls.keys() {
+        writeln!(out, "    fn_{f:04x};")?;
+    }
+    for (f, callees) in &calls {
+        for callee in callees {
+            writeln!(out, "    fn_{f:04x} -> fn_{callee:04x};")?;
+        }
+    }
+    writeln!(out, "}}")
+}
+
+// Instructions that end a basic block
+fn ends_block(insn: &Insn) -> bool {
+    matches!(
+        insn,
+        Insn::Jmp(_) | Insn::Jt(..) | Insn::Jf(..) | Insn::Call(_) | Insn::Ret | Insn::Halt
+    )
+}
+
+// Addresses that can be executed after `insn`, targets in registers are
+// unknown.
+fn succs(insn: &Insn, next: u16) -> Vec<(u16, Edge)> {
+    let mut succs = vec![];
+    match *insn {
+        Insn::Ret | Insn::Halt => {}
+        Insn::Jmp(a) => succs.push((a, Edge::Jump)),
+        Insn::Jt(_, b) => {
+            succs.push((b, Edge::Branch(true)));
+            succs.push((next, Edge::Branch(false)));
+        }
+        Insn::Jf(_, b) => {
+            succs.push((b, Edge::Branch(false)));
+            succs.push((next, Edge::Branch(true)));
+        }
+        _ => succs.push((next, Edge::Next)),
+    }
+    succs.retain(|(addr, _)| layout::is_mem(*addr));
+    succs
+}
+
+// Escape a string for a DOT label
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
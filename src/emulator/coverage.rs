@@ -0,0 +1,143 @@
+use std::io::{self, Write};
+
+use crate::emulator::{Cpu, layout};
+
+const FETCHED: u8 = 1; // fetched as part of an instruction
+const INSN: u8 = 2; // first word of an executed instruction
+const READ: u8 = 4;
+const WRITTEN: u8 = 8;
+
+/// Record how each memory word has been accessed by the program: executed as
+/// code, read or written as data. Registers are not tracked.
+pub struct Coverage {
+    flags: Vec<u8>,
+}
+
+impl Default for Coverage {
+    fn default() -> Coverage {
+        Coverage {
+            flags: vec![0; layout::MEM_SIZE as usize],
+        }
+    }
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage::default()
+    }
+
+    /// Returns true if the word was fetched as an opcode or an operand.
+    pub fn executed(&self, addr: u16) -> bool {
+        self.has(addr, FETCHED)
+    }
+
+    /// Returns true if an instruction starting at `addr` was executed.
+    pub fn insn_start(&self, addr: u16) -> bool {
+        self.has(addr, INSN)
+    }
+
+    pub fn read(&self, addr: u16) -> bool {
+        self.has(addr, READ)
+    }
+
+    pub fn written(&self, addr: u16) -> bool {
+        self.has(addr, WRITTEN)
+    }
+
+    fn has(&self, addr: u16, flag: u8) -> bool {
+        self.flags
+            .get(addr as usize)
+            .is_some_and(|flags| flags & flag != 0)
+    }
+
+    pub(crate) fn on_fetch(&mut self, addr: u16) {
+        self.flags[addr as usize] |= FETCHED;
+    }
+
+    pub(crate) fn on_insn(&mut self, addr: u16) {
+        self.flags[addr as usize] |= INSN;
+    }
+
+    pub(crate) fn on_read(&mut self, addr: u16) {
+        self.flags[addr as usize] |= READ;
+    }
+
+    pub(crate) fn on_write(&mut self, addr: u16) {
+        self.flags[addr as usize] |= WRITTEN;
+    }
+
+    /// Number of words executed, read, written and never touched in the first
+    /// `len` words of memory.
+    pub fn summary(&self, len: u16) -> String {
+        let flags = &self.flags[..len as usize];
+        let count = |flag: u8| flags.iter().filter(|f| *f & flag != 0).count();
+        let percent = |count: usize| count as f64 * 100.0 / (len as f64).max(1.0);
+
+        let executed = count(FETCHED);
+        let read = count(READ);
+        let written = count(WRITTEN);
+        let both = flags
+            .iter()
+            .filter(|f| *f & FETCHED != 0 && *f & (READ | WRITTEN) != 0)
+            .count();
+        let untouched = flags.iter().filter(|f| **f == 0).count();
+
+        let mut out = format!("Coverage of {len} words\n");
+        for (name, count) in [
+            ("executed", executed),
+            ("read", read),
+            ("written", written),
+            ("code used as data", both),
+            ("untouched", untouched),
+        ] {
+            out.push_str(&format!(
+                "  {name:<18} {count:>6} ({:.2}%)\n",
+                percent(count)
+            ));
+        }
+        out
+    }
+
+    /// Write the disassembly of the first `len` words of memory, each line
+    /// starts with the accesses to its words: `x` executed, `r` read and `w`
+    /// written. Executed instructions are decoded, other words are shown as
+    /// data.
+    pub fn annotate(&self, cpu: &Cpu, len: u16, out: &mut impl Write) -> io::Result<()> {
+        let mut addr = 0;
+        while addr < len {
+            let decoded = if self.insn_start(addr) {
+                cpu.decode(addr).ok()
+            } else {
+                None
+            };
+            let next = decoded.map_or(addr + 1, |(_, next)| next.min(len));
+            let words = addr..next;
+            let mark = |flag: u8, c: char| {
+                if words.clone().any(|a| self.has(a, flag)) {
+                    c
+                } else {
+                    '-'
+                }
+            };
+            write!(
+                out,
+                "{}{}{} {addr:05} (0x{addr:04x})  ",
+                mark(FETCHED, 'x'),
+                mark(READ, 'r'),
+                mark(WRITTEN, 'w')
+            )?;
+            match decoded {
+                Some((insn, _)) => writeln!(out, "{insn}")?,
+                None => {
+                    let word = cpu.mem[addr as usize];
+                    match u8::try_from(word).ok().filter(|c| c.is_ascii_graphic()) {
+                        Some(c) => writeln!(out, ".word 0x{word:04x} '{}'", c as char)?,
+                        None => writeln!(out, ".word 0x{word:04x}")?,
+                    }
+                }
+            }
+            addr = next;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,238 @@
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+use crate::emulator::{Insn, insn, layout};
+
+// Minimum number of printable characters shown as a string
+const MIN_STRING: usize = 4;
+
+// Number of words on a `.word` line
+const WORDS_PER_LINE: usize = 8;
+
+/// Reference to a code address by a `call` or a jump.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Xref {
+    pub from: u16, // address of the instruction
+    pub insn: Insn,
+}
+
+/// Disassembly that follows the control flow from a set of entry points.
+///
+/// Words that are not reached as instructions are data, so tables and strings
+/// don't misalign the code that follows them. Targets of `call` are labeled
+/// `fn_XXXX` and targets of jumps `loc_XXXX`.
+pub struct Disassembly {
+    insns: BTreeMap<u16, (Insn, u16)>, // instruction and next address by address
+    xrefs: BTreeMap<u16, Vec<Xref>>,   // references by target
+    len: u16,
+}
+
+impl Disassembly {
+    /// Disassemble the first `len` words of `mem` starting from `roots`.
+    pub fn new(mem: &[u16], len: u16, roots: &[u16]) -> Disassembly {
+        let mut insns = BTreeMap::new();
+        let mut code = vec![false; len as usize];
+        let mut todo: Vec<u16> = roots.to_vec();
+
+        while let Some(addr) = todo.pop() {
+            if addr >= len || code[addr as usize] {
+                continue;
+            }
+            let Ok((insn, next)) = insn::decode(mem, addr) else {
+                continue;
+            };
+            // Instructions can't overlap
+            if (addr..next.min(len)).any(|a| code[a as usize]) {
+                continue;
+            }
+            for a in addr..next.min(len) {
+                code[a as usize] = true;
+            }
+            insns.insert(addr, (insn, next));
+
+            let (target, falls_through) = flow(&insn);
+            if let Some(target) = target.filter(|t| layout::is_mem(*t)) {
+                todo.push(target);
+            }
+            if let Ok((following, _)) = insn::decode(mem, next)
+                && let Some(target) = loaded_target(&insn, &following)
+            {
+                todo.push(target);
+            }
+            if falls_through {
+                todo.push(next);
+            }
+        }
+
+        let mut xrefs: BTreeMap<u16, Vec<Xref>> = BTreeMap::new();
+        for (&from, &(insn, next)) in &insns {
+            if let (Some(target), _) = flow(&insn)
+                && insns.contains_key(&target)
+            {
+                xrefs.entry(target).or_default().push(Xref { from, insn });
+            }
+            if let Some(&(following, _)) = insns.get(&next)
+                && let Some(target) = loaded_target(&insn, &following)
+                && insns.contains_key(&target)
+            {
+                let xref = Xref {
+                    from: next,
+                    insn: following,
+                };
+                xrefs.entry(target).or_default().push(xref);
+            }
+        }
+        for refs in xrefs.values_mut() {
+            refs.sort_by_key(|x| x.from);
+        }
+
+        Disassembly { insns, xrefs, len }
+    }
+
+    /// Label of the code at `addr` if it is the target of a `call` or a jump.
+    pub fn label(&self, addr: u16) -> Option<String> {
+        let xrefs = self.xrefs.get(&addr)?;
+        if xrefs.iter().any(|x| matches!(x.insn, Insn::Call(_))) {
+            Some(format!("fn_{addr:04x}"))
+        } else {
+            Some(format!("loc_{addr:04x}"))
+        }
+    }
+
+    /// Addresses labeled `fn_XXXX`, sorted.
+    pub fn functions(&self) -> impl Iterator<Item = u16> + '_ {
+        self.xrefs
+            .iter()
+            .filter(|(_, xrefs)| xrefs.iter().any(|x| matches!(x.insn, Insn::Call(_))))
+            .map(|(addr, _)| *addr)
+    }
+
+    /// References to `addr`, sorted by address.
+    pub fn xrefs(&self, addr: u16) -> &[Xref] {
+        self.xrefs.get(&addr).map_or(&[], Vec::as_slice)
+    }
+
+    /// Returns the instruction starting at `addr` and the address of the next
+    /// one, if `addr` was reached as code.
+    pub fn insn(&self, addr: u16) -> Option<(Insn, u16)> {
+        self.insns.get(&addr).copied()
+    }
+
+    /// Iterate over the instructions sorted by address.
+    pub fn insns(&self) -> impl Iterator<Item = (u16, Insn, u16)> + '_ {
+        self.insns
+            .iter()
+            .map(|(addr, (insn, next))| (*addr, *insn, *next))
+    }
+
+    /// Write the listing, data is shown with `.word` and `.string`
+    /// directives. With `raw` the instructions are shown in their raw format.
+    pub fn write(&self, mem: &[u16], out: &mut impl Write, raw: bool) -> io::Result<()> {
+        let mut addr = 0;
+        while addr < self.len {
+            if let Some((insn, next)) = self.insn(addr) {
+                if let Some(label) = self.label(addr) {
+                    let refs: Vec<String> = self
+                        .xrefs(addr)
+                        .iter()
+                        .map(|x| {
+                            format!("0x{:04x} ({})", x.from, x.insn.name().to_ascii_lowercase())
+                        })
+                        .collect();
+                    writeln!(out, "{label}:  ; xrefs: {}", refs.join(", "))?;
+                }
+                if raw {
+                    writeln!(out, "Mem[{addr:05} (0x{addr:04x})]-> {insn:#}")?;
+                } else {
+                    let text = insn.with_labels(|a| self.label(a));
+                    writeln!(out, "Mem[{addr:05} (0x{addr:04x})]-> {text}")?;
+                }
+                addr = next;
+                continue;
+            }
+            let start = addr;
+            while addr < self.len && !self.insns.contains_key(&addr) {
+                addr += 1;
+            }
+            write_data(out, start, &mem[start as usize..addr as usize])?;
+        }
+        Ok(())
+    }
+}
+
+// Target of the instruction if it is a literal, and whether the execution can
+// continue with the next instruction.
+fn flow(insn: &Insn) -> (Option<u16>, bool) {
+    match *insn {
+        Insn::Halt | Insn::Ret => (None, false),
+        Insn::Jmp(a) => (Some(a), false),
+        Insn::Jt(_, b) | Insn::Jf(_, b) => (Some(b), true),
+        Insn::Call(a) => (Some(a), true),
+        _ => (None, true),
+    }
+}
+
+// Address loaded by `insn` in the register that `following` calls or jumps
+// to, like `set r0 1287` followed by `call r0`.
+pub(crate) fn loaded_target(insn: &Insn, following: &Insn) -> Option<u16> {
+    match (*insn, *following) {
+        (Insn::Set(reg, addr), Insn::Call(target) | Insn::Jmp(target))
+            if reg == target && layout::is_mem(addr) =>
+        {
+            Some(addr)
+        }
+        _ => None,
+    }
+}
+
+// Characters that can be shown in a `.string`
+pub(crate) fn printable(word: u16) -> Option<char> {
+    u8::try_from(word)
+        .ok()
+        .filter(|c| c.is_ascii_graphic() || *c == b' ' || *c == b'\n')
+        .map(char::from)
+}
+
+// Write the data stored at `start`, long enough runs of printable characters
+// are shown as strings.
+fn write_data(out: &mut impl Write, start: u16, words: &[u16]) -> io::Result<()> {
+    let mut idx = 0;
+    let mut pending = 0; // first word not written yet
+    while idx < words.len() {
+        let run = words[idx..]
+            .iter()
+            .take_while(|w| printable(**w).is_some())
+            .count();
+        if run < MIN_STRING {
+            idx += 1;
+            continue;
+        }
+        write_words(out, start + pending as u16, &words[pending..idx])?;
+        let s: String = words[idx..idx + run]
+            .iter()
+            .filter_map(|w| printable(*w))
+            .collect();
+        let addr = start + idx as u16;
+        writeln!(
+            out,
+            "Mem[{addr:05} (0x{addr:04x})]-> .string \"{}\"",
+            s.escape_default()
+        )?;
+        idx += run;
+        pending = idx;
+    }
+    write_words(out, start + pending as u16, &words[pending..])
+}
+
+fn write_words(out: &mut impl Write, start: u16, words: &[u16]) -> io::Result<()> {
+    for (idx, chunk) in words.chunks(WORDS_PER_LINE).enumerate() {
+        let addr = start + (idx * WORDS_PER_LINE) as u16;
+        let values: Vec<String> = chunk.iter().map(|w| format!("0x{w:04x}")).collect();
+        writeln!(
+            out,
+            "Mem[{addr:05} (0x{addr:04x})]-> .word {}",
+            values.join(", ")
+        )?;
+    }
+    Ok(())
+}
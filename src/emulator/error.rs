@@ -0,0 +1,55 @@
+use std::fmt;
+
+/// Errors raised by the virtual machine.
+///
+/// When an instruction fails the CPU is left in a consistent state with the
+/// instruction pointer on the faulting instruction, so it can be inspected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VmError {
+    /// The value is neither a literal nor a register (32776..65535).
+    InvalidOperand(u16),
+    /// The opcode doesn't match any instruction.
+    InvalidOpcode(u16),
+    /// The address is not in memory.
+    InvalidAddress(u16),
+    /// The instruction pointer left the memory.
+    IpOutOfRange(u16),
+    /// The destination of an instruction is a literal instead of a register.
+    WriteToLiteral(u16),
+    /// ROMs are made of 16 bit words so they must have an even number of bytes.
+    OddRomSize(usize),
+    /// The ROM, given in words, doesn't fit in memory.
+    RomTooLarge(usize),
+    /// Popped an empty stack.
+    EmptyStack,
+    /// Modulo by zero.
+    DivideByZero,
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmError::InvalidOperand(v) => write!(f, "invalid operand {v} (0x{v:04x})"),
+            VmError::InvalidOpcode(v) => write!(f, "invalid opcode {v} (0x{v:04x})"),
+            VmError::InvalidAddress(v) => write!(f, "address {v} (0x{v:04x}) is not in memory"),
+            VmError::IpOutOfRange(ip) => write!(f, "IP {ip} (0x{ip:04x}) is out of memory"),
+            VmError::WriteToLiteral(v) => {
+                write!(
+                    f,
+                    "cannot write to literal {v} (0x{v:04x}), a register is expected"
+                )
+            }
+            VmError::OddRomSize(len) => write!(f, "ROM size is odd ({len} bytes)"),
+            VmError::RomTooLarge(words) => {
+                write!(
+                    f,
+                    "ROM is too large ({words} words), it doesn't fit in memory"
+                )
+            }
+            VmError::EmptyStack => write!(f, "cannot pop empty stack"),
+            VmError::DivideByZero => write!(f, "division by zero"),
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
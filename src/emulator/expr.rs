@@ -0,0 +1,395 @@
+//! Small expression language used by conditional breakpoints.
+//!
+//! Expressions are evaluated against the state of the CPU, for example
+//! `r7 != 0 && mem[0x0aac] == 5` or `stack.len > 20`. Supported terms are:
+//!   - numbers in decimal or hexadecimal (`0x` prefix)
+//!   - registers `r0` to `r7` and the instruction pointer `ip`
+//!   - memory content `mem[<expr>]`
+//!   - the stack depth `stack.len` and its elements `stack[<expr>]`, `stack[0]`
+//!     being the top of the stack
+//!
+//! Operators, from lowest to highest precedence: `||`, `&&`, comparisons
+//! (`==`, `!=`, `<`, `<=`, `>`, `>=`), `+` and `-`, and the unary `!`.
+//! Comparisons and logical operators evaluate to 1 or 0, any nonzero value is
+//! true.
+
+use crate::emulator::layout;
+use std::fmt;
+
+/// The parts of the CPU that expressions can read.
+pub struct Env<'a> {
+    pub ip: u16,
+    pub regs: &'a [u16],
+    pub mem: &'a [u16],
+    pub stack: &'a [u16],
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExprError(String);
+
+impl fmt::Display for ExprError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ExprError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BinOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Add,
+    Sub,
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Num(i64),
+    Ip,
+    Reg(usize),
+    Mem(Box<Node>),
+    StackLen,
+    Stack(Box<Node>),
+    Not(Box<Node>),
+    Bin(BinOp, Box<Node>, Box<Node>),
+}
+
+/// A parsed expression. It displays as the source it was parsed from.
+#[derive(Debug, Clone)]
+pub struct Expr {
+    src: String,
+    node: Node,
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.src)
+    }
+}
+
+impl Expr {
+    pub fn parse(src: &str) -> Result<Expr, ExprError> {
+        let tokens = tokenize(src)?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let node = parser.or()?;
+        if let Some(tok) = parser.peek() {
+            return Err(ExprError(format!("unexpected {tok}")));
+        }
+        Ok(Expr {
+            src: src.trim().to_string(),
+            node,
+        })
+    }
+
+    pub fn eval(&self, env: &Env) -> Result<i64, ExprError> {
+        eval(&self.node, env)
+    }
+
+    /// Evaluate the expression as a condition.
+    pub fn is_true(&self, env: &Env) -> Result<bool, ExprError> {
+        Ok(self.eval(env)? != 0)
+    }
+}
+
+fn eval(node: &Node, env: &Env) -> Result<i64, ExprError> {
+    let value = match node {
+        Node::Num(n) => *n,
+        Node::Ip => env.ip as i64,
+        Node::Reg(r) => env.regs[*r] as i64,
+        Node::Mem(addr) => {
+            let addr = eval(addr, env)?;
+            let value = usize::try_from(addr).ok().and_then(|a| env.mem.get(a));
+            *value.ok_or_else(|| ExprError(format!("mem[{addr}] is out of memory")))? as i64
+        }
+        Node::StackLen => env.stack.len() as i64,
+        Node::Stack(idx) => {
+            let idx = eval(idx, env)?;
+            let value = usize::try_from(idx)
+                .ok()
+                .and_then(|i| env.stack.iter().rev().nth(i));
+            *value.ok_or_else(|| ExprError(format!("stack[{idx}] is out of the stack")))? as i64
+        }
+        Node::Not(e) => (eval(e, env)? == 0) as i64,
+        Node::Bin(BinOp::Or, a, b) => (eval(a, env)? != 0 || eval(b, env)? != 0) as i64,
+        Node::Bin(BinOp::And, a, b) => (eval(a, env)? != 0 && eval(b, env)? != 0) as i64,
+        Node::Bin(op, a, b) => {
+            let a = eval(a, env)?;
+            let b = eval(b, env)?;
+            match op {
+                BinOp::Eq => (a == b) as i64,
+                BinOp::Ne => (a != b) as i64,
+                BinOp::Lt => (a < b) as i64,
+                BinOp::Le => (a <= b) as i64,
+                BinOp::Gt => (a > b) as i64,
+                BinOp::Ge => (a >= b) as i64,
+                BinOp::Add => a.wrapping_add(b),
+                BinOp::Sub => a.wrapping_sub(b),
+                BinOp::Or | BinOp::And => unreachable!("handled above"),
+            }
+        }
+    };
+    Ok(value)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Num(i64),
+    Ident(String),
+    Op(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Num(n) => write!(f, "'{n}'"),
+            Token::Ident(s) => write!(f, "'{s}'"),
+            Token::Op(s) => write!(f, "'{s}'"),
+        }
+    }
+}
+
+// Longest operators first so that `<=` is not read as `<` followed by `=`.
+const OPERATORS: &[&str] = &[
+    "||", "&&", "==", "!=", "<=", ">=", "<", ">", "+", "-", "!", "(", ")", "[", "]",
+];
+
+fn tokenize(src: &str) -> Result<Vec<Token>, ExprError> {
+    let mut tokens = vec![];
+    let mut rest = src.trim_start();
+
+    while let Some(c) = rest.chars().next() {
+        if c.is_ascii_digit() {
+            let len = rest
+                .find(|c: char| !c.is_ascii_alphanumeric())
+                .unwrap_or(rest.len());
+            let word = &rest[..len];
+            let n = match word.strip_prefix("0x") {
+                Some(hex) => i64::from_str_radix(hex, 16),
+                None => word.parse::<i64>(),
+            }
+            .map_err(|_| ExprError(format!("invalid number '{word}'")))?;
+            tokens.push(Token::Num(n));
+            rest = &rest[len..];
+        } else if c.is_ascii_alphabetic() {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+                .unwrap_or(rest.len());
+            tokens.push(Token::Ident(rest[..len].to_string()));
+            rest = &rest[len..];
+        } else if let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(**op)) {
+            tokens.push(Token::Op(op));
+            rest = &rest[op.len()..];
+        } else {
+            return Err(ExprError(format!("unexpected character '{c}'")));
+        }
+        rest = rest.trim_start();
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let tok = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        tok
+    }
+
+    // Consume the operator if it is the next token
+    fn eat(&mut self, op: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Op(o)) if *o == op) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), ExprError> {
+        if self.eat(op) {
+            Ok(())
+        } else {
+            match self.peek() {
+                Some(tok) => Err(ExprError(format!("expected '{op}', found {tok}"))),
+                None => Err(ExprError(format!("expected '{op}'"))),
+            }
+        }
+    }
+
+    fn or(&mut self) -> Result<Node, ExprError> {
+        let mut node = self.and()?;
+        while self.eat("||") {
+            node = Node::Bin(BinOp::Or, Box::new(node), Box::new(self.and()?));
+        }
+        Ok(node)
+    }
+
+    fn and(&mut self) -> Result<Node, ExprError> {
+        let mut node = self.cmp()?;
+        while self.eat("&&") {
+            node = Node::Bin(BinOp::And, Box::new(node), Box::new(self.cmp()?));
+        }
+        Ok(node)
+    }
+
+    fn cmp(&mut self) -> Result<Node, ExprError> {
+        let node = self.sum()?;
+        let ops = [
+            ("==", BinOp::Eq),
+            ("!=", BinOp::Ne),
+            ("<=", BinOp::Le),
+            (">=", BinOp::Ge),
+            ("<", BinOp::Lt),
+            (">", BinOp::Gt),
+        ];
+        for (tok, op) in ops {
+            if self.eat(tok) {
+                return Ok(Node::Bin(op, Box::new(node), Box::new(self.sum()?)));
+            }
+        }
+        Ok(node)
+    }
+
+    fn sum(&mut self) -> Result<Node, ExprError> {
+        let mut node = self.unary()?;
+        loop {
+            let op = if self.eat("+") {
+                BinOp::Add
+            } else if self.eat("-") {
+                BinOp::Sub
+            } else {
+                return Ok(node);
+            };
+            node = Node::Bin(op, Box::new(node), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Node, ExprError> {
+        if self.eat("!") {
+            return Ok(Node::Not(Box::new(self.unary()?)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Node, ExprError> {
+        match self.next() {
+            Some(Token::Num(n)) => Ok(Node::Num(n)),
+            Some(Token::Op("(")) => {
+                let node = self.or()?;
+                self.expect(")")?;
+                Ok(node)
+            }
+            Some(Token::Ident(id)) => match id.as_str() {
+                "ip" => Ok(Node::Ip),
+                "mem" => Ok(Node::Mem(Box::new(self.index()?))),
+                "stack" => Ok(Node::Stack(Box::new(self.index()?))),
+                "stack.len" => Ok(Node::StackLen),
+                _ => match id.strip_prefix('r').and_then(|r| r.parse::<usize>().ok()) {
+                    Some(r) if r < layout::NUM_REGS as usize => Ok(Node::Reg(r)),
+                    _ => Err(ExprError(format!("unknown name '{id}'"))),
+                },
+            },
+            Some(tok) => Err(ExprError(format!("unexpected {tok}"))),
+            None => Err(ExprError("unexpected end of expression".to_string())),
+        }
+    }
+
+    // Parse `[<expr>]`
+    fn index(&mut self) -> Result<Node, ExprError> {
+        self.expect("[")?;
+        let node = self.or()?;
+        self.expect("]")?;
+        Ok(node)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(src: &str) -> i64 {
+        let env = Env {
+            ip: 7,
+            regs: &[10, 0, 0, 0, 0, 0, 0, 3],
+            mem: &[5, 6, 2],
+            stack: &[1, 2, 9],
+        };
+        Expr::parse(src).unwrap().eval(&env).unwrap()
+    }
+
+    fn parse_error(src: &str) -> String {
+        Expr::parse(src).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(eval("1 + 2 == 3"), 1);
+        assert_eq!(eval("0 && 0 || 1"), 1);
+        assert_eq!(eval("1 || 0 && 0"), 1);
+        assert_eq!(eval("1 == 1 && 2 > 3"), 0);
+        assert_eq!(eval("!0 + 1"), 2);
+        assert_eq!(eval("!(0 + 1)"), 0);
+        assert_eq!(eval("5 - 2 - 1"), 2);
+    }
+
+    #[test]
+    fn terms() {
+        assert_eq!(eval("ip"), 7);
+        assert_eq!(eval("r0 + r7"), 13);
+        assert_eq!(eval("mem[0x2]"), 2);
+        assert_eq!(eval("mem[mem[2] - 1]"), 6);
+        assert_eq!(eval("stack.len"), 3);
+        assert_eq!(eval("stack[0]"), 9);
+    }
+
+    #[test]
+    fn source_is_kept() {
+        assert_eq!(Expr::parse("  r0 != 0 ").unwrap().to_string(), "r0 != 0");
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(parse_error("1 +"), "unexpected end of expression");
+        assert_eq!(parse_error("r8"), "unknown name 'r8'");
+        assert_eq!(parse_error("(1"), "expected ')'");
+        assert_eq!(parse_error("mem[1 2"), "expected ']', found '2'");
+        assert_eq!(parse_error("1 2"), "unexpected '2'");
+        assert_eq!(parse_error("0xzz"), "invalid number '0xzz'");
+        assert_eq!(parse_error("r0 = 1"), "unexpected character '='");
+    }
+
+    #[test]
+    fn eval_errors() {
+        let env = Env {
+            ip: 0,
+            regs: &[0; 8],
+            mem: &[0; 4],
+            stack: &[],
+        };
+        let err = |src: &str| {
+            Expr::parse(src)
+                .unwrap()
+                .eval(&env)
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(err("mem[4]"), "mem[4] is out of memory");
+        assert_eq!(err("stack[0]"), "stack[0] is out of the stack");
+    }
+}
@@ -0,0 +1,204 @@
+use crate::emulator::{Frame, Mismatch};
+use std::collections::VecDeque;
+
+/// Default number of instructions kept in the history.
+pub const DEFAULT_LIMIT: usize = 100_000;
+
+/// Modification of the machine recorded in the undo log. Each entry keeps what
+/// is needed to revert it.
+#[derive(Debug, Clone)]
+pub(crate) enum Change {
+    /// Start of the instruction at the given address.
+    Step(u16),
+    /// Memory or register write, the old value is kept.
+    Write(u16, u16),
+    /// A value was pushed on the stack.
+    Push,
+    /// The value was popped from the stack.
+    Pop(u16),
+    /// The character was consumed from the input.
+    Input(u8),
+    /// A frame was pushed on the shadow call stack.
+    FramePush,
+    /// The frame was popped from the shadow call stack.
+    FramePop(Frame),
+    /// An event was added to the recorded session.
+    Record,
+    /// The replayed session moved to the next event.
+    Replay,
+    /// Mismatch counters of the shadow call stack before they were updated.
+    Mismatch(u64, Option<Mismatch>),
+}
+
+/// Undo log of the executed instructions.
+///
+/// Only the last `limit` instructions are kept to bound memory, a limit of 0
+/// disables reverse execution. The instruction in progress is always recorded
+/// so it can be reverted if it fails.
+#[derive(Debug)]
+pub struct History {
+    log: VecDeque<Change>,
+    steps: usize, // number of instructions in the log
+    limit: usize,
+}
+
+impl Default for History {
+    fn default() -> History {
+        History::new(DEFAULT_LIMIT)
+    }
+}
+
+impl History {
+    pub fn new(limit: usize) -> History {
+        History {
+            log: VecDeque::new(),
+            steps: 0,
+            limit,
+        }
+    }
+
+    /// Number of instructions that can be reverted.
+    pub fn len(&self) -> usize {
+        self.steps
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps == 0
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Change the number of instructions kept, the oldest ones are dropped
+    /// if needed.
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+        self.trim();
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.log.clear();
+        self.steps = 0;
+    }
+
+    // Start recording the instruction at `ip`.
+    pub(crate) fn begin(&mut self, ip: u16) {
+        self.log.push_back(Change::Step(ip));
+        self.steps += 1;
+    }
+
+    pub(crate) fn record(&mut self, change: Change) {
+        self.log.push_back(change);
+    }
+
+    // The instruction in progress completed, drop the oldest ones above the
+    // limit.
+    pub(crate) fn end(&mut self) {
+        self.trim();
+    }
+
+    // Remove the last change. Reaching the start of an instruction ends the
+    // record of that instruction.
+    pub(crate) fn pop(&mut self) -> Option<Change> {
+        let change = self.log.pop_back()?;
+        if let Change::Step(_) = change {
+            self.steps -= 1;
+        }
+        Some(change)
+    }
+
+    fn trim(&mut self) {
+        while self.steps > self.limit {
+            // The log always starts with the beginning of an instruction
+            self.log.pop_front();
+            while !matches!(self.log.front(), None | Some(Change::Step(_))) {
+                self.log.pop_front();
+            }
+            self.steps -= 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::emulator::testing::{R0, R1, R2, load};
+    use crate::emulator::{Cpu, Frame, QueueInput, StopReason, VmError};
+
+    #[rustfmt::skip]
+    const PROGRAM: &[u16] = &[
+        1, R0, 5,           // 0: set r0 5
+        2, R0,              // 3: push r0
+        9, R1, R0, R0,      // 5: add r1 r0 r0
+        16, 100, R1,        // 9: wmem 100 r1
+        17, 16,             // 12: call 16
+        0,                  // 14: halt
+        21,                 // 15: noop
+        3, R2,              // 16: pop r2
+        2, R2,              // 18: push r2
+        18,                 // 20: ret
+    ];
+
+    type State = (u16, [u16; 8], Vec<u16>, u16, Vec<Frame>);
+
+    fn state(cpu: &Cpu) -> State {
+        (
+            cpu.ip,
+            cpu.regs,
+            cpu.stack.clone(),
+            cpu.mem[100],
+            cpu.call_stack.frames().to_vec(),
+        )
+    }
+
+    #[test]
+    fn reverse_steps_restore_the_state() {
+        let mut cpu = load(PROGRAM);
+        let mut states = vec![state(&cpu)];
+        for _ in 0..8 {
+            assert_eq!(cpu.step(), StopReason::StepLimit);
+            states.push(state(&cpu));
+        }
+        assert_eq!(cpu.ip, 14);
+        assert_eq!(cpu.mem[100], 10);
+        assert_eq!(cpu.history.len(), 8);
+
+        states.pop();
+        while let Some(expected) = states.pop() {
+            assert_eq!(cpu.reverse_step(), StopReason::StepLimit);
+            assert_eq!(state(&cpu), expected);
+        }
+        assert_eq!(cpu.reverse_step(), StopReason::HistoryStart);
+    }
+
+    #[test]
+    fn limit_drops_the_oldest_steps() {
+        let mut cpu = load(PROGRAM);
+        cpu.history.set_limit(3);
+        cpu.cont_for(6);
+        assert_eq!(cpu.history.len(), 3);
+        for _ in 0..3 {
+            assert_eq!(cpu.reverse_step(), StopReason::StepLimit);
+        }
+        assert_eq!(cpu.ip, 9);
+        assert_eq!(cpu.reverse_step(), StopReason::HistoryStart);
+    }
+
+    #[test]
+    fn failed_instruction_is_rolled_back_without_history() {
+        // in 5; in r0
+        let mut cpu = load(&[20, 5, 20, R0]);
+        let mut input = QueueInput::new();
+        input.push("ab");
+        cpu.set_input(Box::new(input));
+        cpu.history.set_limit(0);
+
+        assert_eq!(cpu.step(), StopReason::Error(VmError::WriteToLiteral(5)));
+        assert_eq!(cpu.ip, 0);
+        cpu.ip = 2;
+        assert_eq!(cpu.step(), StopReason::StepLimit);
+        assert_eq!(cpu.regs[0], b'a' as u16);
+        assert!(cpu.history.is_empty());
+        assert_eq!(cpu.reverse_step(), StopReason::HistoryStart);
+    }
+}
@@ -0,0 +1,113 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+
+/// A source of input lines for the `in` instruction.
+///
+/// The spec says that once input starts it continues until a newline is
+/// encountered, so sources are read a whole line at a time and the CPU hands
+/// the characters out one per `in` instruction.
+pub trait Input {
+    /// Returns the next line including its trailing newline, or `None` once the
+    /// source is exhausted.
+    fn read_line(&mut self) -> Option<String>;
+}
+
+/// Read lines from the terminal.
+pub struct StdinInput;
+
+impl Input for StdinInput {
+    fn read_line(&mut self) -> Option<String> {
+        // Make sure the prompt printed by the program is visible
+        io::stdout().flush().ok()?;
+
+        let mut line = String::new();
+        match io::stdin().read_line(&mut line) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => Some(ensure_newline(line)),
+        }
+    }
+}
+
+/// Read lines from a file.
+pub struct FileInput {
+    reader: BufReader<File>,
+}
+
+impl FileInput {
+    pub fn open(filename: &str) -> io::Result<FileInput> {
+        let f = File::open(filename)?;
+        Ok(FileInput {
+            reader: BufReader::new(f),
+        })
+    }
+}
+
+impl Input for FileInput {
+    fn read_line(&mut self) -> Option<String> {
+        let mut line = String::new();
+        match self.reader.read_line(&mut line) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => Some(ensure_newline(line)),
+        }
+    }
+}
+
+/// Read lines from an in-memory queue.
+#[derive(Default)]
+pub struct QueueInput {
+    lines: VecDeque<String>,
+}
+
+impl QueueInput {
+    pub fn new() -> QueueInput {
+        QueueInput::default()
+    }
+
+    /// Add a line at the end of the queue. A newline is appended if missing.
+    pub fn push(&mut self, line: &str) {
+        self.lines.push_back(ensure_newline(line.to_string()));
+    }
+}
+
+impl Input for QueueInput {
+    fn read_line(&mut self) -> Option<String> {
+        self.lines.pop_front()
+    }
+}
+
+/// Read lines from a source until it is exhausted, then from another one.
+pub struct ChainInput {
+    first: Option<Box<dyn Input>>, // dropped once exhausted
+    then: Box<dyn Input>,
+}
+
+impl ChainInput {
+    pub fn new(first: Box<dyn Input>, then: Box<dyn Input>) -> ChainInput {
+        ChainInput {
+            first: Some(first),
+            then,
+        }
+    }
+}
+
+impl Input for ChainInput {
+    fn read_line(&mut self) -> Option<String> {
+        if let Some(first) = &mut self.first {
+            match first.read_line() {
+                Some(line) => return Some(line),
+                None => self.first = None,
+            }
+        }
+        self.then.read_line()
+    }
+}
+
+// The last line of a file may not be terminated but the program expects to
+// see a newline to know that the input is complete.
+fn ensure_newline(mut line: String) -> String {
+    if !line.ends_with('\n') {
+        line.push('\n');
+    }
+    line
+}
@@ -1,79 +1,57 @@
-use crate::emulator::Cpu;
+use crate::emulator::{Cpu, VmError, layout};
 use std::fmt;
 
 #[derive(Debug, Clone, Copy)]
 struct OpCode {
     name: &'static str,
     arity: usize, // number of parameters
+    build: fn([u16; 3]) -> Insn,
 }
 
+// Indexed by opcode
 #[rustfmt::skip]
-const OPCODES: &[OpCode] = &[
-    OpCode {name: "Halt", arity: 0}, // 0
-    OpCode {name: "Set" , arity: 2},
-    OpCode {name: "Push", arity: 1},
-    OpCode {name: "Pop" , arity: 1},
-    OpCode {name: "Eq"  , arity: 3},
-    OpCode {name: "Gt"  , arity: 3}, // 5
-    OpCode {name: "Jmp" , arity: 1},
-    OpCode {name: "Jt"  , arity: 2},
-    OpCode {name: "Jf"  , arity: 2},
-    OpCode {name: "Add" , arity: 3},
-    OpCode {name: "Mult", arity: 3}, // 10
-    OpCode {name: "Mod" , arity: 3},
-    OpCode {name: "And" , arity: 3},
-    OpCode {name: "Or"  , arity: 3},
-    OpCode {name: "Not" , arity: 2},
-    OpCode {name: "Rmem", arity: 2}, // 15
-    OpCode {name: "Wmem", arity: 2},
-    OpCode {name: "Call", arity: 1},
-    OpCode {name: "Ret" , arity: 0},
-    OpCode {name: "Out" , arity: 1},
-    OpCode {name: "In"  , arity: 1}, // 20
-    OpCode {name: "Noop", arity: 0}, // 21
+const OPCODES: [OpCode; 22] = [
+    OpCode {name: "Halt", arity: 0, build: |_| Insn::Halt}, // 0
+    OpCode {name: "Set" , arity: 2, build: |a| Insn::Set(a[0], a[1])},
+    OpCode {name: "Push", arity: 1, build: |a| Insn::Push(a[0])},
+    OpCode {name: "Pop" , arity: 1, build: |a| Insn::Pop(a[0])},
+    OpCode {name: "Eq"  , arity: 3, build: |a| Insn::Eq(a[0], a[1], a[2])},
+    OpCode {name: "Gt"  , arity: 3, build: |a| Insn::Gt(a[0], a[1], a[2])}, // 5
+    OpCode {name: "Jmp" , arity: 1, build: |a| Insn::Jmp(a[0])},
+    OpCode {name: "Jt"  , arity: 2, build: |a| Insn::Jt(a[0], a[1])},
+    OpCode {name: "Jf"  , arity: 2, build: |a| Insn::Jf(a[0], a[1])},
+    OpCode {name: "Add" , arity: 3, build: |a| Insn::Add(a[0], a[1], a[2])},
+    OpCode {name: "Mult", arity: 3, build: |a| Insn::Mult(a[0], a[1], a[2])}, // 10
+    OpCode {name: "Mod" , arity: 3, build: |a| Insn::Mod(a[0], a[1], a[2])},
+    OpCode {name: "And" , arity: 3, build: |a| Insn::And(a[0], a[1], a[2])},
+    OpCode {name: "Or"  , arity: 3, build: |a| Insn::Or(a[0], a[1], a[2])},
+    OpCode {name: "Not" , arity: 2, build: |a| Insn::Not(a[0], a[1])},
+    OpCode {name: "Rmem", arity: 2, build: |a| Insn::Rmem(a[0], a[1])}, // 15
+    OpCode {name: "Wmem", arity: 2, build: |a| Insn::Wmem(a[0], a[1])},
+    OpCode {name: "Call", arity: 1, build: |a| Insn::Call(a[0])},
+    OpCode {name: "Ret" , arity: 0, build: |_| Insn::Ret},
+    OpCode {name: "Out" , arity: 1, build: |a| Insn::Out(a[0])},
+    OpCode {name: "In"  , arity: 1, build: |a| Insn::In(a[0])}, // 20
+    OpCode {name: "Noop", arity: 0, build: |_| Insn::Noop}, // 21
 ];
 
-#[rustfmt::skip]
-pub fn gen_insn(opcode: usize, cpu: &mut Cpu) -> Option<Insn> {
-    if opcode >= OPCODES.len() {
-        println!(
-            "Unknown opcode {opcode} at {:05} (0x{:05x}). CPU halted",
-            cpu.ip, cpu.ip
-        );
-        cpu.halt("unknown opcode");
-        return None;
-    }
+/// Build the instruction for `opcode`, its operands are read using `fetch`.
+pub fn gen_insn(
+    opcode: u16,
+    mut fetch: impl FnMut() -> Result<u16, VmError>,
+) -> Result<Insn, VmError> {
+    let Some(op) = OPCODES.get(opcode as usize) else {
+        return Err(VmError::InvalidOpcode(opcode));
+    };
 
-    let op = OPCODES[opcode];
-    let args: Vec<u16> = (0..op.arity).map(|_| cpu.fetch()).collect();
-    match op.name {
-        "Halt" => Some(Insn::Halt),
-        "Set"  => Some(Insn::Set(args[0], args[1])),
-        "Push" => Some(Insn::Push(args[0])),
-        "Pop"  => Some(Insn::Pop(args[0])),
-        "Eq"   => Some(Insn::Eq(args[0], args[1], args[2])),
-        "Gt"   => Some(Insn::Gt(args[0], args[1], args[2])),
-        "Jmp"  => Some(Insn::Jmp(args[0])),
-        "Jt"   => Some(Insn::Jt(args[0], args[1])),
-        "Jf"   => Some(Insn::Jf(args[0], args[1])),
-        "Add"  => Some(Insn::Add(args[0], args[1], args[2])),
-        "Mult" => Some(Insn::Mult(args[0], args[1], args[2])),
-        "Mod"  => Some(Insn::Mod(args[0], args[1], args[2])),
-        "And"  => Some(Insn::And(args[0], args[1], args[2])),
-        "Or"   => Some(Insn::Or(args[0], args[1], args[2])),
-        "Not"  => Some(Insn::Not(args[0], args[1])),
-        "Rmem" => Some(Insn::Rmem(args[0], args[1])),
-        "Wmem" => Some(Insn::Wmem(args[0], args[1])),
-        "Call" => Some(Insn::Call(args[0])),
-        "Ret"  => Some(Insn::Ret),
-        "Out"  => Some(Insn::Out(std::char::from_u32(args[0] as u32).expect("failed to convert char"))),
-        "In"   => Some(Insn::In(std::char::from_u32(args[0] as u32).expect("failed to convert char"))),
-        "Noop" => Some(Insn::Noop),
-        _ => panic!("unreachable"),
+    let mut args = [0; 3];
+    for arg in args.iter_mut().take(op.arity) {
+        *arg = fetch()?;
     }
+    Ok((op.build)(args))
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Insn {
     Add(u16, u16, u16),
     And(u16, u16, u16),
@@ -81,7 +59,7 @@ pub enum Insn {
     Eq(u16, u16, u16),
     Gt(u16, u16, u16),
     Halt,
-    In(char),
+    In(u16),
     Jf(u16, u16),
     Jmp(u16),
     Jt(u16, u16),
@@ -90,7 +68,7 @@ pub enum Insn {
     Noop,
     Not(u16, u16),
     Or(u16, u16, u16),
-    Out(char),
+    Out(u16),
     Pop(u16),
     Push(u16),
     Ret,
@@ -100,8 +78,132 @@ pub enum Insn {
 }
 
 #[rustfmt::skip]
+impl Insn {
+    pub fn opcode(&self) -> u16 {
+        match self {
+            Insn::Halt       => 0,
+            Insn::Set(..)    => 1,
+            Insn::Push(_)    => 2,
+            Insn::Pop(_)     => 3,
+            Insn::Eq(..)     => 4,
+            Insn::Gt(..)     => 5,
+            Insn::Jmp(_)     => 6,
+            Insn::Jt(..)     => 7,
+            Insn::Jf(..)     => 8,
+            Insn::Add(..)    => 9,
+            Insn::Mult(..)   => 10,
+            Insn::Mod(..)    => 11,
+            Insn::And(..)    => 12,
+            Insn::Or(..)     => 13,
+            Insn::Not(..)    => 14,
+            Insn::Rmem(..)   => 15,
+            Insn::Wmem(..)   => 16,
+            Insn::Call(_)    => 17,
+            Insn::Ret        => 18,
+            Insn::Out(_)     => 19,
+            Insn::In(_)      => 20,
+            Insn::Noop       => 21,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        OPCODES[self.opcode() as usize].name
+    }
+
+    /// Raw operands in the order they are stored in memory.
+    pub fn operands(&self) -> Vec<u16> {
+        match *self {
+            Insn::Halt | Insn::Ret | Insn::Noop => vec![],
+            Insn::Push(a) | Insn::Pop(a) | Insn::Jmp(a) | Insn::Call(a) | Insn::Out(a)
+            | Insn::In(a) => vec![a],
+            Insn::Set(a, b) | Insn::Jt(a, b) | Insn::Jf(a, b) | Insn::Not(a, b)
+            | Insn::Rmem(a, b) | Insn::Wmem(a, b) => vec![a, b],
+            Insn::Eq(a, b, c) | Insn::Gt(a, b, c) | Insn::Add(a, b, c) | Insn::Mult(a, b, c)
+            | Insn::Mod(a, b, c) | Insn::And(a, b, c) | Insn::Or(a, b, c) => vec![a, b, c],
+        }
+    }
+}
+
+/// The instruction is shown with register names and literals formatted after
+/// their use, like `add  r0 r1 32767`. The alternate flag (`{:#}`) gives the
+/// raw format with the opcode and the operands in hexadecimal.
 impl fmt::Display for Insn {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if f.alternate() {
+            return self.fmt_raw(f);
+        }
+        f.pad(&self.with_labels(|_| None))
+    }
+}
+
+// How a literal operand is shown
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    Value,
+    Addr,
+    Char,
+}
+
+struct Operand {
+    value: u16,
+    kind: Kind,
+    label: Option<String>, // shown instead of an address
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let v = self.value;
+        if let Some(label) = &self.label {
+            return write!(f, "{label}");
+        }
+        if layout::is_reg(v) {
+            return write!(f, "r{}", v - layout::REG_MIN);
+        }
+        if v > layout::REG_MAX {
+            return write!(f, "<invalid 0x{v:04x}>");
+        }
+        match (self.kind, literal_char(v)) {
+            (Kind::Char, Some(c)) => write!(f, "'{}'", c.escape_default()),
+            (Kind::Addr, _) => write!(f, "0x{v:04x}"),
+            _ => write!(f, "{v}"),
+        }
+    }
+}
+
+impl Insn {
+    /// Format the instruction like `Display`, literal addresses for which
+    /// `label` returns a name are replaced by it.
+    pub fn with_labels(&self, label: impl Fn(u16) -> Option<String>) -> String {
+        let name = self.name().to_ascii_lowercase();
+        let operands = self.operands();
+        // Operands are aligned, a mnemonic alone is not padded
+        let mut s = if operands.is_empty() {
+            name
+        } else {
+            format!("{name:<4}")
+        };
+        for (value, kind) in operands.into_iter().zip(self.kinds()) {
+            let label = match kind {
+                Kind::Addr if layout::is_mem(value) => label(value),
+                _ => None,
+            };
+            s.push_str(&format!(" {}", Operand { value, kind, label }));
+        }
+        s
+    }
+
+    // Kind of each operand
+    fn kinds(&self) -> [Kind; 3] {
+        match self {
+            Insn::Jmp(_) | Insn::Call(_) | Insn::Wmem(..) => [Kind::Addr, Kind::Value, Kind::Value],
+            Insn::Jt(..) | Insn::Jf(..) | Insn::Rmem(..) => [Kind::Value, Kind::Addr, Kind::Value],
+            Insn::Out(_) => [Kind::Char, Kind::Value, Kind::Value],
+            _ => [Kind::Value; 3],
+        }
+    }
+
+    #[rustfmt::skip]
+    fn fmt_raw(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Insn::Halt          => write!(f, "00: Halt"),
             Insn::Set(a, b)     => write!(f, "01: Set   {a:04X} {b:04X}"),
@@ -122,14 +224,42 @@ impl fmt::Display for Insn {
             Insn::Wmem(a, b)    => write!(f, "16: Wmem  {a:04X} {b:04X}"),
             Insn::Call(a)       => write!(f, "17: Call  {a:04X}"),
             Insn::Ret           => write!(f, "18: Ret"),
-            Insn::Out(a)        => write!(f, "19: Out   <{a}>"),
-            Insn::In(a)         => write!(f, "20: In    <{a}>"),
+            Insn::Out(a)        => match literal_char(*a) {
+                Some(c)         => write!(f, "19: Out   <{}>", c.escape_default()),
+                None            => write!(f, "19: Out   {a:04X}"),
+            },
+            Insn::In(a)         => write!(f, "20: In    {a:04X}"),
             Insn::Noop          => write!(f, "21: Noop"),
         }
     }
 }
 
-pub fn get(cpu: &mut Cpu) -> Option<Insn> {
-    let opcode = cpu.fetch();
-    gen_insn(opcode as usize, cpu)
+// Return the character of a literal operand, registers and values that are
+// not ascii are returned as None.
+fn literal_char(value: u16) -> Option<char> {
+    u8::try_from(value)
+        .ok()
+        .filter(u8::is_ascii)
+        .map(char::from)
+}
+
+/// Fetch the instruction at the instruction pointer and move it to the next
+/// instruction.
+pub fn get(cpu: &mut Cpu) -> Result<Insn, VmError> {
+    let opcode = cpu.fetch()?;
+    gen_insn(opcode, || cpu.fetch())
+}
+
+/// Decode the instruction stored at `addr` without executing it. Returns the
+/// instruction and the address of the next one.
+pub fn decode(mem: &[u16], addr: u16) -> Result<(Insn, u16), VmError> {
+    let mut ip = addr;
+    let mut fetch = || {
+        let word = *mem.get(ip as usize).ok_or(VmError::IpOutOfRange(ip))?;
+        ip += 1;
+        Ok(word)
+    };
+    let opcode = fetch()?;
+    let insn = gen_insn(opcode, fetch)?;
+    Ok((insn, ip))
 }
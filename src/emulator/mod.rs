@@ -1,14 +1,48 @@
+mod breakpoint;
+mod callstack;
+mod cfg;
+mod coverage;
+mod disasm;
+mod error;
+pub mod expr;
+mod history;
+mod input;
 mod insn;
-
-macro_rules! vprint {
-    ($verbose:expr, $($arg:tt)*) => {
-        if $verbose {
-            println!($($arg)*);
-        }
-    };
-}
-
-mod layout {
+mod output;
+mod profile;
+mod session;
+mod snapshot;
+mod stop;
+mod strings;
+mod symbols;
+mod trace;
+mod watchpoint;
+
+use std::collections::VecDeque;
+use std::io::{self, Write};
+
+pub use breakpoint::{Breakpoint, Breakpoints};
+pub use callstack::{CallStack, Frame, Mismatch};
+pub use cfg::{BasicBlock, Cfg, Edge};
+pub use coverage::Coverage;
+pub use disasm::{Disassembly, Xref};
+pub use error::VmError;
+pub use expr::{Env, Expr, ExprError};
+use history::Change;
+pub use history::History;
+pub use input::{ChainInput, FileInput, Input, QueueInput, StdinInput};
+pub use insn::Insn;
+pub use output::{BufferOutput, FileOutput, Output, TeeOutput, TerminalOutput};
+pub use profile::{FnStats, Profiler};
+use session::Replay;
+pub use session::{Divergence, Event, Session};
+pub use stop::StopReason;
+pub use strings::{CapturedString, FoundString, StringCapture};
+pub use symbols::Symbols;
+pub use trace::{TraceFormat, Tracer};
+pub use watchpoint::{Access, Target, WatchHit, Watchpoint, Watchpoints};
+
+pub mod layout {
     /// Total number of addressable memory words.
     /// Memory addresses range from 0 to MEM_SIZE - 1.
     pub const MEM_SIZE: u16 = 32_768;
@@ -28,34 +62,41 @@ mod layout {
     /// Highest address mapped to a register.
     pub const REG_MAX: u16 = REG_MIN + NUM_REGS - 1;
 
+    /// Returns true if `addr` is a memory address.
     pub fn is_mem(addr: u16) -> bool {
         (MEM_MIN..=MEM_MAX).contains(&addr)
     }
 
+    /// Returns true if `addr` refers to a register.
     pub fn is_reg(addr: u16) -> bool {
         (REG_MIN..=REG_MAX).contains(&addr)
     }
 }
 
-#[derive(PartialEq, Eq)]
-enum State {
-    Running,
-    Stopped,
-}
-
 pub struct Cpu {
-    pub mem: [u16; layout::MEM_SIZE as usize], // The size will depend of the ROMs
+    mem: [u16; layout::MEM_SIZE as usize], // The size will depend of the ROMs
     pub regs: [u16; layout::NUM_REGS as usize],
     pub stack: Vec<u16>,
     pub ip: u16,        // Instruction pointer
     pub footprint: u16, // keep the program's memory footprint
-    state: State,
-    breakpoint: Option<u16>,
+    pub breakpoints: Breakpoints,
+    pub watchpoints: Watchpoints,
+    pub call_stack: CallStack,
+    pub history: History,
+    pub tracer: Option<Tracer>,
+    pub profiler: Option<Profiler>,
+    pub coverage: Option<Coverage>,
+    pub strings: Option<StringCapture>,
+    recording: Option<Session>, // events are added while it is set
+    replay: Option<Replay>,
+    input: Box<dyn Input>,
+    input_buf: VecDeque<u8>, // characters of the current line not yet consumed by `in`
+    output: Box<dyn Output>,
+    decoded: Option<Vec<Option<(Insn, u16)>>>, // instruction and next address per address
 }
 
-#[allow(unused)]
 impl Cpu {
-    pub fn load(roms: Vec<u8>) -> Cpu {
+    pub fn load(roms: Vec<u8>) -> Result<Cpu, VmError> {
         // The format of the ROM is each number is stored as a 16 bit little endian pair
         // Example:
         //   - roms[0] -> low byte of mem[0]
@@ -64,9 +105,13 @@ impl Cpu {
         //   - roms[i] -> low byte of mem[i]
         //   - roms[i + 1] -> high byte of mem[i]
         // Programs are loaded into memory starting at address 0
-        assert!(roms.len().is_multiple_of(2), "ROMs size is odd");
+        if !roms.len().is_multiple_of(2) {
+            return Err(VmError::OddRomSize(roms.len()));
+        }
         let footprint = roms.len() / 2;
-        assert!(footprint <= layout::MEM_MAX as usize);
+        if footprint > layout::MEM_SIZE as usize {
+            return Err(VmError::RomTooLarge(footprint));
+        }
 
         let mut cpu = Cpu {
             mem: [0; layout::MEM_SIZE as usize],
@@ -74,8 +119,20 @@ impl Cpu {
             stack: vec![],
             ip: 0,
             footprint: footprint as u16,
-            state: State::Stopped,
-            breakpoint: None,
+            breakpoints: Breakpoints::default(),
+            watchpoints: Watchpoints::default(),
+            call_stack: CallStack::default(),
+            history: History::default(),
+            recording: None,
+            tracer: None,
+            profiler: None,
+            coverage: None,
+            strings: None,
+            replay: None,
+            input: Box::new(StdinInput),
+            input_buf: VecDeque::new(),
+            output: Box::new(TerminalOutput::new()),
+            decoded: Some(vec![None; layout::MEM_SIZE as usize]),
         };
 
         for (idx, chunk) in roms.chunks_exact(2).enumerate() {
@@ -84,100 +141,333 @@ impl Cpu {
             cpu.mem[idx] = high | low;
         }
 
-        cpu
+        Ok(cpu)
     }
 
-    pub fn set_breakpoint(&mut self, addr: u16) {
-        if layout::is_mem(addr) {
-            self.breakpoint = Some(addr);
-            println!("Breakpoint set at {:05} (0x{:04x})", addr, addr);
-        } else {
-            println!("Failed to set addr, {addr} is not in memory");
+    /// View of the CPU used to evaluate expressions.
+    pub fn env(&self) -> Env<'_> {
+        Env {
+            ip: self.ip,
+            regs: &self.regs,
+            mem: &self.mem,
+            stack: &self.stack,
+        }
+    }
+
+    /// Decode the instruction stored at `addr` without executing it. Returns
+    /// the instruction and the address of the next one.
+    pub fn decode(&self, addr: u16) -> Result<(Insn, u16), VmError> {
+        insn::decode(&self.mem, addr)
+    }
+
+    /// Enable or disable the cache of decoded instructions, it is enabled by
+    /// default.
+    pub fn set_decode_cache(&mut self, enabled: bool) {
+        self.decoded = enabled.then(|| vec![None; layout::MEM_SIZE as usize]);
+    }
+
+    /// Content of the memory.
+    pub fn mem(&self) -> &[u16] {
+        &self.mem
+    }
+
+    /// Write `value` at the memory address `addr` on behalf of the user, it
+    /// is not seen by the watchpoints, the coverage or the history.
+    pub fn write_mem(&mut self, addr: u16, value: u16) -> Result<(), VmError> {
+        if !layout::is_mem(addr) {
+            return Err(VmError::InvalidAddress(addr));
+        }
+        self.mem[addr as usize] = value;
+        self.invalidate_addr(addr);
+        Ok(())
+    }
+
+    // Forget the decoded instructions.
+    fn invalidate_decoded(&mut self) {
+        if let Some(decoded) = &mut self.decoded {
+            decoded.fill(None);
         }
     }
 
+    // Forget the instructions that contain the word at `addr`, instructions
+    // are at most 4 words long.
+    fn invalidate_addr(&mut self, addr: u16) {
+        if let Some(decoded) = &mut self.decoded {
+            for a in addr.saturating_sub(3)..=addr {
+                decoded[a as usize] = None;
+            }
+        }
+    }
+
+    // Fetch the instruction at IP, from the cache if it was already decoded,
+    // and move IP to the next instruction.
+    fn fetch_insn(&mut self) -> Result<Insn, VmError> {
+        let ip = self.ip;
+        let cached = self
+            .decoded
+            .as_ref()
+            .and_then(|d| d.get(ip as usize).copied().flatten());
+        if let Some((insn, next)) = cached {
+            if let Some(coverage) = &mut self.coverage {
+                for addr in ip..next {
+                    coverage.on_fetch(addr);
+                }
+            }
+            self.ip = next;
+            return Ok(insn);
+        }
+        let insn = insn::get(self)?;
+        if let Some(decoded) = &mut self.decoded {
+            decoded[ip as usize] = Some((insn, self.ip));
+        }
+        Ok(insn)
+    }
+
+    /// Replace the source used by the `in` instruction. Characters of a line
+    /// already started are still delivered before reading from the new source.
+    pub fn set_input(&mut self, input: Box<dyn Input>) {
+        self.input = input;
+    }
+
+    /// Replace the sink of the `out` instruction and return the previous one.
+    pub fn set_output(&mut self, output: Box<dyn Output>) -> Box<dyn Output> {
+        std::mem::replace(&mut self.output, output)
+    }
+
+    /// Read the lines of `input` before the ones of the current source.
+    pub fn feed(&mut self, input: Box<dyn Input>) {
+        let rest = std::mem::replace(&mut self.input, Box::new(QueueInput::new()));
+        self.input = Box::new(ChainInput::new(input, rest));
+    }
+
+    /// Record the input and output of the program in a new session. The
+    /// history is cleared as it can't revert events of another session.
+    pub fn start_recording(&mut self) {
+        self.recording = Some(Session::new());
+        self.history.clear();
+    }
+
+    /// Stop recording and return the recorded session.
+    pub fn stop_recording(&mut self) -> Option<Session> {
+        self.history.clear();
+        self.recording.take()
+    }
+
+    /// Session being recorded.
+    pub fn recording(&self) -> Option<&Session> {
+        self.recording.as_ref()
+    }
+
+    /// Replay `session`, the `in` instructions read its input instead of the
+    /// input source and the `out` instructions must print its output. The
+    /// history is cleared as it can't revert events of another session.
+    pub fn replay(&mut self, session: Session) {
+        self.replay = Some(Replay { session, pos: 0 });
+        self.history.clear();
+    }
+
+    pub fn stop_replay(&mut self) {
+        self.replay = None;
+        self.history.clear();
+    }
+
+    /// Position of the next event in the replayed session and its length.
+    pub fn replay_progress(&self) -> Option<(usize, usize)> {
+        self.replay.as_ref().map(|r| (r.pos, r.session.len()))
+    }
+
+    // Check the access of the instruction at `ip` against the replayed session
+    // and add it to the recording. `output` is the value printed by `out`, or
+    // None for `in` in which case the character read is returned.
+    fn exchange(&mut self, ip: u16, output: Option<u16>) -> Result<Option<u8>, StopReason> {
+        let c = match &mut self.replay {
+            Some(replay) => match replay.next(ip, output) {
+                Ok(c) => {
+                    self.history.record(Change::Replay);
+                    c
+                }
+                Err(reason) => {
+                    if reason == StopReason::ReplayFinished {
+                        self.replay = None;
+                    }
+                    return Err(reason);
+                }
+            },
+            None if output.is_none() => match self.read_char() {
+                Some(c) => Some(c),
+                None => return Err(StopReason::WaitingForInput),
+            },
+            None => None,
+        };
+        if let Some(recording) = &mut self.recording {
+            let event = match (output, c) {
+                (Some(v), _) => Event::Out(v),
+                (None, c) => Event::In(c.expect("a character is read")),
+            };
+            recording.push(event);
+            self.history.record(Change::Record);
+        }
+        Ok(c)
+    }
+
+    // Return the next character of the input, reading a new line from the
+    // input source when the current one has been fully consumed.
+    fn read_char(&mut self) -> Option<u8> {
+        if self.input_buf.is_empty() {
+            let line = self.input.read_line()?;
+            self.input_buf.extend(line.bytes());
+        }
+        let c = self.input_buf.pop_front()?;
+        self.history.record(Change::Input(c));
+        Some(c)
+    }
+
     // Resolve the addr, if it is in the memroy range the address is returned
     // and if it is in the register range it is the content of the register that
     // is returned
-    fn resolve_addr(&self, addr: u16) -> u16 {
+    fn resolve_addr(&mut self, addr: u16) -> Result<u16, VmError> {
         if layout::is_mem(addr) {
-            addr
+            Ok(addr)
         } else if layout::is_reg(addr) {
             let reg_id = (addr - layout::REG_MIN) as usize;
-            self.regs[reg_id]
+            self.watchpoints.on_read(addr, self.regs[reg_id]);
+            Ok(self.regs[reg_id])
         } else {
-            panic!("{addr} is not valid memory");
+            Err(VmError::InvalidOperand(addr))
         }
     }
 
-    // Read the value at the given address. If it is in memory range it returns the content
-    // at this address, otherwise it returns the content of the register.
-    pub fn read(&self, addr: u16) -> u16 {
+    // Read the value at the given memory address for an instruction.
+    fn read_mem(&mut self, addr: u16) -> Result<u16, VmError> {
+        if !layout::is_mem(addr) {
+            return Err(VmError::InvalidAddress(addr));
+        }
+        let value = self.mem[addr as usize];
+        self.watchpoints.on_read(addr, value);
+        if let Some(coverage) = &mut self.coverage {
+            coverage.on_read(addr);
+        }
+        if let Some(strings) = &mut self.strings {
+            strings.on_read(addr);
+        }
+        Ok(value)
+    }
+
+    /// Read the value at the given address. If it is in memory range it returns the content
+    /// at this address, otherwise it returns the content of the register.
+    /// Watchpoints are not triggered.
+    pub fn read(&self, addr: u16) -> Result<u16, VmError> {
         if layout::is_mem(addr) {
-            self.mem[addr as usize]
+            Ok(self.mem[addr as usize])
         } else if layout::is_reg(addr) {
             let reg_id = (addr - layout::REG_MIN) as usize;
-            self.regs[reg_id]
+            Ok(self.regs[reg_id])
         } else {
-            panic!("{addr} is not valid memory");
+            Err(VmError::InvalidOperand(addr))
         }
     }
 
-    // Read the value at the given address. If it is in memory range it returns the content
-    // at this address, otherwise it returns the content of the register.
-    fn write(&mut self, addr: u16, value: u16) {
+    // Write the value at the given address. If it is in memory range the memory
+    // is updated, otherwise it is the register.
+    fn write(&mut self, addr: u16, value: u16) -> Result<(), VmError> {
+        let slot = self.slot(addr).ok_or(VmError::InvalidOperand(addr))?;
+        let old = std::mem::replace(slot, value);
+        self.watchpoints.on_write(addr, old, value);
+        self.history.record(Change::Write(addr, old));
+        if layout::is_mem(addr) {
+            self.invalidate_addr(addr);
+            if let Some(coverage) = &mut self.coverage {
+                coverage.on_write(addr);
+            }
+        }
+        Ok(())
+    }
+
+    // Memory word or register at the given address.
+    fn slot(&mut self, addr: u16) -> Option<&mut u16> {
         if layout::is_reg(addr) {
-            let reg_id = (addr - layout::REG_MIN) as usize;
-            self.regs[reg_id] = value;
+            Some(&mut self.regs[(addr - layout::REG_MIN) as usize])
         } else if layout::is_mem(addr) {
-            // It looks like we can write memory
-            self.mem[addr as usize] = value;
+            Some(&mut self.mem[addr as usize])
         } else {
-            panic!("write, {addr} is out of memory");
+            None
+        }
+    }
+
+    fn push(&mut self, value: u16) {
+        self.stack.push(value);
+        self.history.record(Change::Push);
+    }
+
+    fn pop(&mut self) -> Option<u16> {
+        let value = self.stack.pop()?;
+        self.history.record(Change::Pop(value));
+        Some(value)
+    }
+
+    // Write the value into the register given as destination operand of an
+    // instruction. A literal destination is an error.
+    fn write_reg(&mut self, addr: u16, value: u16) -> Result<(), VmError> {
+        if layout::is_reg(addr) {
+            self.write(addr, value)
+        } else if layout::is_mem(addr) {
+            Err(VmError::WriteToLiteral(addr))
+        } else {
+            Err(VmError::InvalidOperand(addr))
         }
     }
 
     fn reset(&mut self) {
         self.regs.fill(0);
+        self.stack.clear();
+        self.call_stack.clear();
+        self.history.clear();
+        if let Some(recording) = &mut self.recording {
+            recording.clear();
+        }
+        if let Some(replay) = &mut self.replay {
+            replay.pos = 0;
+        }
         self.ip = 0;
     }
 
-    fn fetch(&mut self) -> u16 {
-        if self.ip > layout::MEM_MAX {
-            panic!("IP {} is above max mem {}", self.ip, layout::MEM_MAX);
+    fn fetch(&mut self) -> Result<u16, VmError> {
+        if !layout::is_mem(self.ip) {
+            return Err(VmError::IpOutOfRange(self.ip));
         }
         let word = self.mem[self.ip as usize];
+        if let Some(coverage) = &mut self.coverage {
+            coverage.on_fetch(self.ip);
+        }
         self.ip += 1;
-        word
+        Ok(word)
     }
 
-    fn set_ip(&mut self, ip: u16) {
-        assert!(ip >= layout::MEM_MIN);
-        assert!(ip <= layout::MEM_MAX);
+    fn set_ip(&mut self, ip: u16) -> Result<(), VmError> {
+        if !layout::is_mem(ip) {
+            return Err(VmError::IpOutOfRange(ip));
+        }
         self.ip = ip;
+        Ok(())
     }
 
     pub fn print(&self) -> String {
         let mut out = String::new();
 
         out.push_str("   -- Memory --\n");
-        let start = self.ip.saturating_sub(5).max(layout::MEM_MIN);
+        let start = self.ip.saturating_sub(5);
         let end = self.ip.saturating_add(5).min(layout::MEM_MAX);
 
         for addr in start..=end {
             if addr == self.ip {
                 out.push_str(&format!(
                     "=> Mem[{:05} (0x{:04X})]: 0x{:04x}\n",
-                    addr,
-                    addr,
-                    self.read(addr.try_into().unwrap())
+                    addr, addr, self.mem[addr as usize]
                 ));
             } else {
                 out.push_str(&format!(
                     "   Mem[{:05} (0x{:04X})]: 0x{:04x}\n",
-                    addr,
-                    addr,
-                    self.read(addr.try_into().unwrap())
+                    addr, addr, self.mem[addr as usize]
                 ));
             }
         }
@@ -186,7 +476,7 @@ impl Cpu {
         for (idx, reg) in self.regs.iter().enumerate() {
             out.push_str(&format!("[{:1}]:0x{:04x} ", idx, reg));
         }
-        out.push_str("\n");
+        out.push('\n');
 
         out.push_str("   -- Stack (last 10) --\n   ");
         let start = self.stack.len().saturating_sub(10);
@@ -194,220 +484,258 @@ impl Cpu {
         out
     }
 
-    pub fn halt(&mut self, reason: &str) {
-        println!("CPU halted: {}", reason);
-        self.state = State::Stopped;
+    /// Execute one instruction. Returns [`StopReason::StepLimit`] if it was
+    /// executed normally, or [`StopReason::Watchpoint`] if it triggered a
+    /// watchpoint. Otherwise the instruction pointer is left on the
+    /// instruction that stopped the execution, so the CPU can be inspected and
+    /// resumed.
+    pub fn step(&mut self) -> StopReason {
+        let ip = self.ip;
+        // The instruction is decoded before it runs as it may overwrite itself
+        let traced = match self.tracer {
+            Some(_) => self.decode(ip).ok().map(|(insn, _)| (insn, self.regs)),
+            None => None,
+        };
+        let current = self.call_stack.frames().last().map(|frame| frame.target);
+        self.history.begin(ip);
+        let reason = match self.exec() {
+            Ok(None) => {
+                self.history.end();
+                if let Some(profiler) = &mut self.profiler {
+                    profiler.record(ip, current, self.call_stack.frames());
+                }
+                if let Some(coverage) = &mut self.coverage {
+                    coverage.on_insn(ip);
+                }
+                if let (Some(tracer), Some((insn, regs))) = (&mut self.tracer, traced) {
+                    tracer.record(ip, &insn, &regs, &self.regs, self.stack.len());
+                }
+                // Watchpoints stop the execution once the instruction is done
+                return match self.watchpoints.take_hit(ip) {
+                    Some(hit) => StopReason::Watchpoint(hit),
+                    None => StopReason::StepLimit,
+                };
+            }
+            Ok(Some(reason)) => reason,
+            Err(e) => StopReason::Error(e),
+        };
+        // The instruction didn't complete so its accesses are not reported
+        // and what it may have changed is reverted
+        self.watchpoints.take_hit(ip);
+        self.undo_step();
+        self.ip = ip;
+        reason
+    }
+
+    /// Revert the last executed instruction using the history.
+    pub fn reverse_step(&mut self) -> StopReason {
+        match self.undo_step() {
+            Some(_) => StopReason::StepLimit,
+            None => StopReason::HistoryStart,
+        }
+    }
+
+    /// Revert instructions until the instruction pointer reaches a
+    /// breakpoint, an instruction that triggers a write or change watchpoint
+    /// is reverted, or the history is exhausted.
+    pub fn reverse_cont(&mut self) -> StopReason {
+        loop {
+            let Some(writes) = self.undo_step() else {
+                return StopReason::HistoryStart;
+            };
+            for (addr, old, new) in writes {
+                if let Some(hit) = self.watchpoints.find_write(addr, old, new, self.ip) {
+                    return StopReason::Watchpoint(hit);
+                }
+            }
+            if self.breakpoints.matches(&self.env()) {
+                return StopReason::Breakpoint(self.ip);
+            }
+        }
     }
 
-    pub fn step(&mut self, verbose: bool) {
-        let Some(insn) = insn::get(self) else { return };
+    // Revert the changes of the last instruction recorded in the history.
+    // Returns the reverted writes as (address, old value, new value), or None
+    // if the history is empty.
+    fn undo_step(&mut self) -> Option<Vec<(u16, u16, u16)>> {
+        let mut writes = vec![];
+        loop {
+            match self.history.pop()? {
+                Change::Step(ip) => {
+                    self.ip = ip;
+                    return Some(writes);
+                }
+                Change::Write(addr, old) => {
+                    let slot = self.slot(addr).expect("only valid writes are recorded");
+                    let new = std::mem::replace(slot, old);
+                    writes.push((addr, old, new));
+                    if layout::is_mem(addr) {
+                        self.invalidate_addr(addr);
+                    }
+                }
+                Change::Push => {
+                    self.stack.pop();
+                }
+                Change::Pop(value) => self.stack.push(value),
+                Change::Input(c) => self.input_buf.push_front(c),
+                Change::FramePush => self.call_stack.pop(),
+                Change::FramePop(frame) => self.call_stack.push(frame),
+                Change::Record => {
+                    if let Some(recording) = &mut self.recording {
+                        recording.pop();
+                    }
+                }
+                Change::Replay => {
+                    if let Some(replay) = &mut self.replay {
+                        replay.pos = replay.pos.saturating_sub(1);
+                    }
+                }
+                Change::Mismatch(count, last) => {
+                    self.call_stack.mismatches = count;
+                    self.call_stack.last_mismatch = last;
+                }
+            }
+        }
+    }
+
+    // Execute the instruction at IP. Returns the reason if it stops the
+    // execution.
+    fn exec(&mut self) -> Result<Option<StopReason>, VmError> {
+        let ip = self.ip;
+        let insn = self.fetch_insn()?;
         match insn {
             insn::Insn::Add(a, b, c) => {
                 // We are expecting a to be a register, it will be checked
                 // when writting
-                let valb = self.resolve_addr(b);
-                let valc = self.resolve_addr(c);
-                vprint!(
-                    verbose,
-                    "IP {:05} (0x{:04x}), Add {a} {valb} {valc}",
-                    self.ip,
-                    self.ip
-                );
-                self.write(a, (valb + valc) % layout::MEM_SIZE);
+                let valb = self.resolve_addr(b)?;
+                let valc = self.resolve_addr(c)?;
+                self.write_reg(a, valb.wrapping_add(valc) % layout::MEM_SIZE)?;
             }
             insn::Insn::And(a, b, c) => {
-                let valb = self.resolve_addr(b);
-                let valc = self.resolve_addr(c);
-                vprint!(
-                    verbose,
-                    "IP {:05} (0x{:04x}), And {a} {valb} {valc}",
-                    self.ip,
-                    self.ip
-                );
-                self.write(a, valb & valc);
+                let valb = self.resolve_addr(b)?;
+                let valc = self.resolve_addr(c)?;
+                self.write_reg(a, valb & valc)?;
             }
             insn::Insn::Call(a) => {
-                self.stack.push(self.ip);
-                let addr = self.resolve_addr(a);
-                vprint!(
-                    verbose,
-                    "IP {:05} (0x{:04x}), Call: Push {} on the stack, set ip to {addr}",
-                    self.ip,
-                    self.ip,
-                    self.ip
+                let addr = self.resolve_addr(a)?;
+                let ret = self.ip;
+                self.set_ip(addr)?;
+                self.push(ret);
+                self.call_stack.on_call(
+                    Frame {
+                        call_site: ret - 2, // call takes one operand
+                        target: addr,
+                        ret,
+                        sp: self.stack.len(),
+                    },
+                    &mut self.history,
                 );
-                self.set_ip(addr)
             }
             insn::Insn::Eq(a, b, c) => {
-                let valb = self.resolve_addr(b);
-                let valc = self.resolve_addr(c);
-                vprint!(
-                    verbose,
-                    "IP {:05} (0x{:04x}), Eq: comparing {valb} == {valc}, set 0x{a:04x}",
-                    self.ip,
-                    self.ip
-                );
+                let valb = self.resolve_addr(b)?;
+                let valc = self.resolve_addr(c)?;
                 if valb == valc {
-                    self.write(a, 1);
+                    self.write_reg(a, 1)?;
                 } else {
-                    self.write(a, 0);
+                    self.write_reg(a, 0)?;
                 }
             }
             insn::Insn::Gt(a, b, c) => {
-                let valb = self.resolve_addr(b);
-                let valc = self.resolve_addr(c);
-                vprint!(
-                    verbose,
-                    "IP {:05} (0x{:04x}), Gt: comparing {valb} > {valc}, set 0x{a:04x}",
-                    self.ip,
-                    self.ip
-                );
+                let valb = self.resolve_addr(b)?;
+                let valc = self.resolve_addr(c)?;
                 if valb > valc {
-                    self.write(a, 1);
+                    self.write_reg(a, 1)?;
                 } else {
-                    self.write(a, 0);
+                    self.write_reg(a, 0)?;
                 }
             }
-            insn::Insn::Halt => self.halt("Reached Halt instruction"),
-            insn::Insn::In(a) => self.halt("instruction not implemented: in"),
+            insn::Insn::Halt => return Ok(Some(StopReason::Halted)),
+            insn::Insn::In(a) => {
+                let c = match self.exchange(ip, None) {
+                    Ok(c) => c.expect("a character is read"),
+                    Err(reason) => return Ok(Some(reason)),
+                };
+                self.write_reg(a, c as u16)?;
+            }
             insn::Insn::Jmp(a) => {
                 // Not sure that address to jmp can be register
-                let value = self.resolve_addr(a);
-                vprint!(
-                    verbose,
-                    "IP {:05} (0x{:04x}), Jmp: set ip to 0x{value:04x}",
-                    self.ip,
-                    self.ip
-                );
-                self.set_ip(value)
+                let value = self.resolve_addr(a)?;
+                self.set_ip(value)?;
             }
             insn::Insn::Jt(a, b) => {
-                let cond = self.resolve_addr(a);
+                let cond = self.resolve_addr(a)?;
                 if cond != 0 {
                     // Not sure that address to jmp can be register
-                    let addr = self.resolve_addr(b);
-                    self.set_ip(addr);
-                    vprint!(
-                        verbose,
-                        "IP {:05} (0x{:04x}), Jt: set ip to 0x{addr:04x} (cond = {cond})",
-                        self.ip,
-                        self.ip
-                    );
-                } else {
-                    vprint!(
-                        verbose,
-                        "IP {:05} (0x{:04x}), Jt: ip not updated (cond = {cond})",
-                        self.ip,
-                        self.ip
-                    );
+                    let addr = self.resolve_addr(b)?;
+                    self.set_ip(addr)?;
                 }
             }
             insn::Insn::Jf(a, b) => {
-                let cond = self.resolve_addr(a);
+                let cond = self.resolve_addr(a)?;
                 if cond == 0 {
                     // Not sure that address to jmp can be register
-                    let addr = self.resolve_addr(b);
-                    self.set_ip(addr);
-                    vprint!(
-                        verbose,
-                        "IP {:05} (0x{:04x}), Jf: set ip to 0x{addr:04x} (cond = {cond})",
-                        self.ip,
-                        self.ip
-                    );
-                } else {
-                    vprint!(
-                        verbose,
-                        "IP {:05} (0x{:04x}), Jf: ip not updated (cond = {cond})",
-                        self.ip,
-                        self.ip
-                    );
+                    let addr = self.resolve_addr(b)?;
+                    self.set_ip(addr)?;
                 }
             }
             insn::Insn::Mod(a, b, c) => {
-                let valb = self.resolve_addr(b);
-                let valc = self.resolve_addr(c);
-                vprint!(
-                    verbose,
-                    "IP {:05} (0x{:04x}), Mod: {a} = {}",
-                    valb % valc,
-                    self.ip,
-                    self.ip
-                );
-                self.write(a, valb % valc);
+                let valb = self.resolve_addr(b)?;
+                let valc = self.resolve_addr(c)?;
+                if valc == 0 {
+                    return Err(VmError::DivideByZero);
+                }
+                self.write_reg(a, valb % valc)?;
             }
             insn::Insn::Mult(a, b, c) => {
                 // Use usize otherwise we hit attempt to multiply with overflow
-                let valb = self.resolve_addr(b) as usize;
-                let valc = self.resolve_addr(c) as usize;
+                let valb = self.resolve_addr(b)? as usize;
+                let valc = self.resolve_addr(c)? as usize;
                 let res = (valb * valc) % (layout::MEM_SIZE as usize);
                 let res = u16::try_from(res).unwrap();
-                vprint!(
-                    verbose,
-                    "IP {:05} (0x{:04x}), Mult: {a} = {res}",
-                    self.ip,
-                    self.ip
-                );
-                self.write(a, res);
+                self.write_reg(a, res)?;
             }
-            insn::Insn::Noop => vprint!(verbose, "IP {:05} (0x{:04x}), Noop", self.ip, self.ip),
+            insn::Insn::Noop => {}
             insn::Insn::Not(a, b) => {
-                let value = self.resolve_addr(b);
+                let value = self.resolve_addr(b)?;
                 let res = !value & 0x7FFF;
-                vprint!(
-                    verbose,
-                    "IP {:05} (0x{:04x}), Not: {a} = {res}",
-                    self.ip,
-                    self.ip
-                );
-                self.write(a, res);
+                self.write_reg(a, res)?;
             }
             insn::Insn::Or(a, b, c) => {
-                let valb = self.resolve_addr(b);
-                let valc = self.resolve_addr(c);
-                let res = valb | valc;
-                vprint!(
-                    verbose,
-                    "IP {:05} (0x{:04x}), Or: {a} = {res}",
-                    self.ip,
-                    self.ip
-                );
-                self.write(a, valb | valc);
+                let valb = self.resolve_addr(b)?;
+                let valc = self.resolve_addr(c)?;
+                self.write_reg(a, valb | valc)?;
             }
-            insn::Insn::Out(a) => print!("{a}"),
-            insn::Insn::Pop(a) => {
-                if let Some(value) = self.stack.pop() {
-                    vprint!(
-                        verbose,
-                        "IP {:05} (0x{:04x}), Pop: {value}",
-                        self.ip,
-                        self.ip
-                    );
-                    self.write(a, value);
-                } else {
-                    self.halt("cannot pop empty stack");
+            insn::Insn::Out(a) => {
+                let value = self.resolve_addr(a)?;
+                if let Err(reason) = self.exchange(ip, Some(value)) {
+                    return Ok(Some(reason));
+                }
+                let c = char::from_u32(value as u32).unwrap_or(char::REPLACEMENT_CHARACTER);
+                if let Some(strings) = &mut self.strings {
+                    strings.on_out(c, !layout::is_reg(a));
                 }
+                self.output.write_char(c);
+            }
+            insn::Insn::Pop(a) => {
+                let value = self.stack.last().copied().ok_or(VmError::EmptyStack)?;
+                self.write_reg(a, value)?;
+                self.pop();
+                self.call_stack.on_pop(self.stack.len(), &mut self.history);
             }
             insn::Insn::Push(a) => {
-                let value = self.resolve_addr(a);
-                vprint!(
-                    verbose,
-                    "IP {:05} (0x{:04x}), Push: {value}",
-                    self.ip,
-                    self.ip
-                );
-                self.stack.push(value);
+                let value = self.resolve_addr(a)?;
+                self.push(value);
             }
             insn::Insn::Ret => {
-                if let Some(addr) = self.stack.pop() {
-                    vprint!(
-                        verbose,
-                        "IP {:05} (0x{:04x}), Ret: set ip to {addr}",
-                        self.ip,
-                        self.ip
-                    );
-                    self.set_ip(addr);
+                // Empty stack is not an error for ret, the spec says to halt
+                if let Some(&addr) = self.stack.last() {
+                    let ip = self.ip - 1; // ret has no operand
+                    self.set_ip(addr)?;
+                    self.call_stack
+                        .on_ret(ip, addr, self.stack.len(), &mut self.history);
+                    self.pop();
                 } else {
-                    self.halt("cannot pop empty stack");
+                    return Ok(Some(StopReason::Halted));
                 }
             }
             insn::Insn::Rmem(a, b) => {
@@ -415,81 +743,199 @@ impl Cpu {
                 //   Rmem 8000 034B => Read the content of Memory[034B] and write it
                 //   Rmem 8000 8002 => Read the content of Reg 8002 that gives you an addr
                 //                     and read the content of Memory[addr] and write it
-                let value = if layout::is_mem(b) {
-                    self.read(b)
-                } else if layout::is_reg(b) {
-                    let addr = self.read(b);
-                    self.read(addr)
-                } else {
-                    panic!("Rmem error: Invalid address {b}");
-                };
-                vprint!(
-                    verbose,
-                    "IP {:05} (0x{:04x}), Rmem: read {value} and try to write it at 0x{:04x}",
-                    self.ip,
-                    self.ip,
-                    a
-                );
-                self.write(a, value);
+                let addr = self.resolve_addr(b)?;
+                let value = self.read_mem(addr)?;
+                self.write_reg(a, value)?;
             }
             insn::Insn::Wmem(a, b) => {
-                let addr = self.read(a);
-                let value = self.resolve_addr(b);
-                vprint!(
-                    verbose,
-                    "IP {:05} (0x{:04x}), Wmem: write {} into memory at 0x{:04x}",
-                    self.ip,
-                    self.ip,
-                    value,
-                    addr
-                );
-                self.write(addr, value);
+                let addr = self.resolve_addr(a)?;
+                let value = self.resolve_addr(b)?;
+                self.write(addr, value)?;
             }
             insn::Insn::Set(a, b) => {
-                let value = self.resolve_addr(b);
-                vprint!(
-                    verbose,
-                    "IP {:05} (0x{:04x}), Set: register 0x{a:04x} to 0x{value:04x}",
-                    self.ip,
-                    self.ip
-                );
-                self.write(a, value)
+                let value = self.resolve_addr(b)?;
+                self.write_reg(a, value)?;
             }
         }
+        Ok(None)
+    }
+
+    /// Execute instructions until something stops the execution.
+    pub fn cont(&mut self) -> StopReason {
+        self.cont_until(|_, _| None)
+    }
+
+    /// Execute at most `limit` instructions.
+    pub fn cont_for(&mut self, limit: u64) -> StopReason {
+        if limit == 0 {
+            return StopReason::StepLimit;
+        }
+        let mut steps = 0;
+        self.cont_until(|_, _| {
+            steps += 1;
+            (steps >= limit).then_some(StopReason::StepLimit)
+        })
     }
 
-    pub fn cont(&mut self, verbose: bool) {
-        self.state = State::Running;
+    /// Execute the instruction at IP, if it is a `call` the execution continues
+    /// until the function returns.
+    pub fn step_over(&mut self) -> StopReason {
+        let Ok((Insn::Call(_), ret)) = self.decode(self.ip) else {
+            return self.step();
+        };
+        // The stack depth is checked to not stop in a recursive call
+        let depth = self.stack.len();
+        self.cont_until(|cpu, _| {
+            (cpu.ip == ret && cpu.stack.len() == depth).then_some(StopReason::Reached(ret))
+        })
+    }
+
+    /// Execute until the current function returns.
+    pub fn finish(&mut self) -> StopReason {
+        // Count the nested calls to know which `ret` leaves the current function
+        let mut depth = 0usize;
+        self.cont_until(|cpu, ip| {
+            match cpu.decode(ip) {
+                Ok((Insn::Call(_), _)) => depth += 1,
+                Ok((Insn::Ret, _)) if depth == 0 => return Some(StopReason::Reached(cpu.ip)),
+                Ok((Insn::Ret, _)) => depth -= 1,
+                _ => {}
+            }
+            None
+        })
+    }
 
-        while self.state == State::Running {
-            self.step(verbose);
+    /// Execute until the instruction pointer reaches `addr`.
+    pub fn until(&mut self, addr: u16) -> StopReason {
+        self.cont_until(|cpu, _| (cpu.ip == addr).then_some(StopReason::Reached(addr)))
+    }
+
+    // Execute instructions until something stops the execution or `done`
+    // returns a reason to stop. `done` is called after each instruction with
+    // the CPU and the address of the executed instruction.
+    fn cont_until(&mut self, mut done: impl FnMut(&Cpu, u16) -> Option<StopReason>) -> StopReason {
+        loop {
+            let ip = self.ip;
+            let reason = self.step();
+            if reason != StopReason::StepLimit {
+                return reason;
+            }
+            if let Some(reason) = done(self, ip) {
+                return reason;
+            }
             // Check if there is a breakpoint
-            if let Some(bp) = self.breakpoint
-                && bp == self.ip
-            {
-                println!("reached breakpoints at {bp}");
-                break;
+            // Fields are borrowed one by one because breakpoints are updated
+            let env = Env {
+                ip: self.ip,
+                regs: &self.regs,
+                mem: &self.mem,
+                stack: &self.stack,
+            };
+            if self.breakpoints.hit(&env) {
+                return StopReason::Breakpoint(self.ip);
             }
         }
     }
 
-    pub fn disassemble(&mut self) {
-        self.reset();
-        let upper = self.footprint;
+    /// Write the disassembly of the program loaded in memory to `out`. The
+    /// code is found by following the control flow from address 0 and from
+    /// the instructions executed so far if the coverage is tracked. With `raw`
+    /// the instructions are shown in their raw format.
+    pub fn disassemble(&self, out: &mut impl Write, raw: bool) -> io::Result<()> {
+        writeln!(
+            out,
+            "Disassemble from {} to {}",
+            layout::MEM_MIN,
+            self.footprint
+        )?;
+        self.disassembly().write(&self.mem, out, raw)
+    }
 
-        println!("Disassemble from {} to {}", layout::MEM_MIN, upper);
-        while self.ip <= upper {
-            print!("Mem[{:05} (0x{:04x})]", self.ip, self.ip);
-            if let Some(insn) = insn::get(self) {
-                println!("-> {}", insn);
-            } else {
-                println!("-> <skipped>");
-            }
+    /// Flow-following disassembly of the program loaded in memory.
+    pub fn disassembly(&self) -> Disassembly {
+        let mut roots = vec![layout::MEM_MIN];
+        if let Some(coverage) = &self.coverage {
+            roots.extend((0..self.footprint).filter(|addr| coverage.insn_start(*addr)));
         }
+        Disassembly::new(&self.mem, self.footprint, &roots)
+    }
+
+    /// Strings of at least `min` characters stored in the program loaded in
+    /// memory.
+    pub fn strings(&self, min: usize) -> Vec<FoundString> {
+        strings::scan(&self.mem, self.footprint, min)
+    }
+
+    /// Control-flow graph of the function starting at `entry`.
+    pub fn cfg(&self, entry: u16) -> Cfg {
+        Cfg::new(&self.mem, entry)
     }
 
-    pub fn run(&mut self, verbose: bool) {
+    /// Write the call graph of the program in the Graphviz DOT format.
+    pub fn call_graph(&self, out: &mut impl Write) -> io::Result<()> {
+        cfg::write_call_graph(&self.mem, &self.disassembly(), out)
+    }
+
+    /// Restart the program from the beginning.
+    pub fn run(&mut self) -> StopReason {
         self.reset();
-        self.cont(verbose);
+        self.cont()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod testing {
+    //! Fixtures shared by the unit tests.
+
+    use crate::emulator::{Cpu, layout};
+
+    pub const R0: u16 = layout::REG_MIN;
+    pub const R1: u16 = layout::REG_MIN + 1;
+    pub const R2: u16 = layout::REG_MIN + 2;
+    pub const MINUS_ONE: u16 = layout::MEM_SIZE - 1;
+
+    /// Load a program given as words.
+    pub fn load(words: &[u16]) -> Cpu {
+        Cpu::load(words.iter().flat_map(|w| w.to_le_bytes()).collect()).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::emulator::StopReason;
+    use crate::emulator::testing::{R0, load};
+
+    #[rustfmt::skip]
+    const SELF_MODIFYING: &[u16] = &[
+        1, R0, 7,   // 0: set r0 7
+        16, 2, 9,   // 3: wmem 2 9, the set becomes set r0 9
+        6, 0,       // 6: jmp 0
+    ];
+
+    #[test]
+    fn rewritten_instruction_is_decoded_again() {
+        let mut cpu = load(SELF_MODIFYING);
+        cpu.set_decode_cache(true);
+        cpu.cont_for(3);
+        assert_eq!((cpu.ip, cpu.regs[0]), (0, 7));
+        cpu.step();
+        assert_eq!(cpu.regs[0], 9);
+    }
+
+    #[test]
+    fn undone_write_is_decoded_again() {
+        let mut cpu = load(SELF_MODIFYING);
+        cpu.set_decode_cache(true);
+        cpu.cont_for(4);
+        assert_eq!(cpu.regs[0], 9);
+        for _ in 0..3 {
+            assert_eq!(cpu.reverse_step(), StopReason::StepLimit);
+        }
+        assert_eq!((cpu.ip, cpu.mem[2]), (3, 7));
+
+        cpu.ip = 0;
+        cpu.regs[0] = 0;
+        cpu.step();
+        assert_eq!(cpu.regs[0], 7);
     }
 }
@@ -0,0 +1,121 @@
+use std::cell::{Cell, RefCell};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::rc::Rc;
+
+/// A sink for the characters printed by the `out` instruction.
+///
+/// Errors are ignored, like a terminal that went away the program can't do
+/// anything about them.
+pub trait Output {
+    fn write_char(&mut self, c: char);
+}
+
+/// Print to the terminal.
+///
+/// Clones share their state so the debugger can keep one to know if the
+/// program left a line unterminated.
+#[derive(Debug, Default, Clone)]
+pub struct TerminalOutput {
+    mid_line: Rc<Cell<bool>>,
+}
+
+impl TerminalOutput {
+    pub fn new() -> TerminalOutput {
+        TerminalOutput::default()
+    }
+
+    /// Returns true if the last line printed by the program is not terminated.
+    pub fn mid_line(&self) -> bool {
+        self.mid_line.get()
+    }
+
+    /// Terminate the line started by the program, if any, so the next message
+    /// starts on its own line.
+    pub fn end_line(&self) {
+        if self.mid_line.replace(false) {
+            println!();
+        }
+    }
+}
+
+impl Output for TerminalOutput {
+    fn write_char(&mut self, c: char) {
+        print!("{c}");
+        self.mid_line.set(c != '\n');
+    }
+}
+
+/// Keep the output in memory.
+///
+/// Clones share the same buffer so the output can be read while the CPU owns
+/// the sink.
+#[derive(Debug, Default, Clone)]
+pub struct BufferOutput {
+    buf: Rc<RefCell<String>>,
+}
+
+impl BufferOutput {
+    pub fn new() -> BufferOutput {
+        BufferOutput::default()
+    }
+
+    pub fn contents(&self) -> String {
+        self.buf.borrow().clone()
+    }
+
+    /// Return the output and empty the buffer.
+    pub fn take(&self) -> String {
+        self.buf.take()
+    }
+}
+
+impl Output for BufferOutput {
+    fn write_char(&mut self, c: char) {
+        self.buf.borrow_mut().push(c);
+    }
+}
+
+/// Write to a file. Lines are flushed as soon as they are complete so the
+/// file can be followed while the program runs.
+pub struct FileOutput {
+    writer: BufWriter<File>,
+}
+
+impl FileOutput {
+    pub fn create(filename: &str) -> io::Result<FileOutput> {
+        let f = File::create(filename)?;
+        Ok(FileOutput {
+            writer: BufWriter::new(f),
+        })
+    }
+}
+
+impl Output for FileOutput {
+    fn write_char(&mut self, c: char) {
+        let mut buf = [0; 4];
+        let _ = self.writer.write_all(c.encode_utf8(&mut buf).as_bytes());
+        if c == '\n' {
+            let _ = self.writer.flush();
+        }
+    }
+}
+
+/// Send the output to two sinks.
+pub struct TeeOutput {
+    first: Box<dyn Output>,
+    second: Box<dyn Output>,
+}
+
+impl TeeOutput {
+    pub fn new(first: Box<dyn Output>, second: Box<dyn Output>) -> TeeOutput {
+        TeeOutput { first, second }
+    }
+}
+
+impl Output for TeeOutput {
+    fn write_char(&mut self, c: char) {
+        self.first.write_char(c);
+        self.second.write_char(c);
+    }
+}
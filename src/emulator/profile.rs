@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+
+use crate::emulator::{Cpu, Frame, Symbols, layout};
+
+/// Counters of a function, identified by the target of the `call`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FnStats {
+    pub calls: u64,
+    /// Instructions executed by the function itself.
+    pub exclusive: u64,
+    /// Instructions executed by the function and the functions it called.
+    pub inclusive: u64,
+    active: u64,     // number of frames of the function on the call stack
+    entered_at: u64, // total when the outermost active frame was pushed
+}
+
+/// Count the instructions executed per address and per function.
+///
+/// Functions are followed using the shadow call stack, instructions executed
+/// outside of any call are not attributed to a function.
+pub struct Profiler {
+    counts: Vec<u64>, // per address
+    functions: HashMap<u16, FnStats>,
+    frames: Vec<u16>, // targets of the frames already seen
+    total: u64,
+}
+
+impl Default for Profiler {
+    fn default() -> Profiler {
+        Profiler {
+            counts: vec![0; layout::MEM_SIZE as usize],
+            functions: HashMap::new(),
+            frames: vec![],
+            total: 0,
+        }
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler::default()
+    }
+
+    /// Number of instructions executed since profiling started.
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Number of times the instruction at `addr` was executed.
+    pub fn count(&self, addr: u16) -> u64 {
+        self.counts.get(addr as usize).copied().unwrap_or(0)
+    }
+
+    /// Counters of the function starting at `addr`. The inclusive count of
+    /// a function still running includes what it executed so far.
+    pub fn function(&self, addr: u16) -> Option<FnStats> {
+        let mut stats = *self.functions.get(&addr)?;
+        if stats.active > 0 {
+            stats.inclusive += self.total - stats.entered_at;
+        }
+        Some(stats)
+    }
+
+    /// Addresses sorted from the most executed, never executed ones are left
+    /// out.
+    pub fn hottest(&self) -> Vec<(u16, u64)> {
+        let mut hot: Vec<(u16, u64)> = self
+            .counts
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(addr, count)| (addr as u16, *count))
+            .collect();
+        hot.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        hot
+    }
+
+    /// Functions sorted by inclusive count.
+    pub fn functions(&self) -> Vec<(u16, FnStats)> {
+        let mut fns: Vec<(u16, FnStats)> = self
+            .functions
+            .keys()
+            .filter_map(|addr| Some((*addr, self.function(*addr)?)))
+            .collect();
+        fns.sort_by(|a, b| b.1.inclusive.cmp(&a.1.inclusive).then(a.0.cmp(&b.0)));
+        fns
+    }
+
+    // Called once the instruction at `ip` has been executed. `current` is the
+    // function the instruction belongs to, `frames` the call stack after the
+    // instruction.
+    pub(crate) fn record(&mut self, ip: u16, current: Option<u16>, frames: &[Frame]) {
+        self.counts[ip as usize] += 1;
+        self.total += 1;
+        if let Some(target) = current {
+            self.functions.entry(target).or_default().exclusive += 1;
+        }
+
+        // An instruction either pushes a frame or drops some
+        while self.frames.len() > frames.len() {
+            let target = self.frames.pop().expect("frames is not empty");
+            let stats = self.functions.entry(target).or_default();
+            stats.active -= 1;
+            if stats.active == 0 {
+                stats.inclusive += self.total - stats.entered_at;
+            }
+        }
+        for frame in &frames[self.frames.len()..] {
+            let stats = self.functions.entry(frame.target).or_default();
+            stats.calls += 1;
+            if stats.active == 0 {
+                stats.entered_at = self.total;
+            }
+            stats.active += 1;
+            self.frames.push(frame.target);
+        }
+    }
+
+    /// Ranked report of the `limit` hottest instructions and functions.
+    pub fn report(&self, cpu: &Cpu, symbols: &Symbols, limit: usize) -> String {
+        let percent = |count: u64| count as f64 * 100.0 / self.total.max(1) as f64;
+        let name = |addr: u16| match symbols.get(addr) {
+            Some(name) => format!(" <{name}>"),
+            None => String::new(),
+        };
+
+        let mut out = format!("{} instruction(s) executed\n", self.total);
+        out.push_str("-- Hottest instructions --\n");
+        out.push_str("     count       %  address\n");
+        for (addr, count) in self.hottest().into_iter().take(limit) {
+            let insn = match cpu.decode(addr) {
+                Ok((insn, _)) => insn.to_string(),
+                Err(e) => format!("<{e}>"),
+            };
+            let _ = writeln!(
+                out,
+                "{count:>10} {:>6.2}%  {addr:05} (0x{addr:04x}){}  {insn}",
+                percent(count),
+                name(addr)
+            );
+        }
+
+        out.push_str("-- Hottest functions --\n");
+        out.push_str("     calls  inclusive       %  exclusive       %  function\n");
+        for (addr, stats) in self.functions().into_iter().take(limit) {
+            let _ = writeln!(
+                out,
+                "{:>10} {:>10} {:>6.2}% {:>10} {:>6.2}%  {addr:05} (0x{addr:04x}){}",
+                stats.calls,
+                stats.inclusive,
+                percent(stats.inclusive),
+                stats.exclusive,
+                percent(stats.exclusive),
+                name(addr)
+            );
+        }
+        out
+    }
+}
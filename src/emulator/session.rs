@@ -0,0 +1,259 @@
+//! Record and replay the characters exchanged by `in` and `out`.
+//!
+//! A session file starts with a header line followed by one event per line,
+//! `in <byte>` for a character read by the program and `out <value>` for a
+//! character it printed. Replaying a session against the same ROM feeds the
+//! recorded input and checks that the program prints exactly the same output.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+
+use crate::emulator::StopReason;
+
+const HEADER: &str = "synacor-session 1";
+
+/// Character exchanged between the program and the console.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    In(u8),
+    Out(u16),
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::In(c) => write!(f, "in {:?}", *c as char),
+            Event::Out(v) => match char::from_u32(*v as u32) {
+                Some(c) => write!(f, "out {c:?}"),
+                None => write!(f, "out 0x{v:04x}"),
+            },
+        }
+    }
+}
+
+/// Ordered list of events.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Session {
+    events: Vec<Event>,
+}
+
+impl Session {
+    pub fn new() -> Session {
+        Session::default()
+    }
+
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    pub(crate) fn push(&mut self, event: Event) {
+        self.events.push(event);
+    }
+
+    pub(crate) fn pop(&mut self) {
+        self.events.pop();
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.events.clear();
+    }
+
+    pub fn load(filename: &str) -> io::Result<Session> {
+        Session::read(BufReader::new(File::open(filename)?))
+    }
+
+    pub fn save(&self, filename: &str) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(filename)?);
+        self.write(&mut out)?;
+        out.flush()
+    }
+
+    pub fn read(input: impl BufRead) -> io::Result<Session> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+        let mut lines = input.lines();
+        match lines.next().transpose()? {
+            Some(line) if line.trim_end() == HEADER => {}
+            _ => return Err(invalid("not a session file".to_string())),
+        }
+
+        let mut session = Session::new();
+        for (idx, line) in lines.enumerate() {
+            let line = line?;
+            let event = match line.split_once(' ') {
+                Some(("in", byte)) => byte.parse().ok().map(Event::In),
+                Some(("out", value)) => value.parse().ok().map(Event::Out),
+                _ => None,
+            };
+            // The header is line 1
+            let event = event.ok_or_else(|| invalid(format!("line {}: invalid event", idx + 2)))?;
+            session.push(event);
+        }
+        Ok(session)
+    }
+
+    pub fn write(&self, out: &mut impl Write) -> io::Result<()> {
+        writeln!(out, "{HEADER}")?;
+        for event in &self.events {
+            match event {
+                Event::In(c) => writeln!(out, "in {c}")?,
+                Event::Out(v) => writeln!(out, "out {v}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// First difference between a replayed session and the execution.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    pub index: usize, // position of the expected event in the session
+    pub ip: u16,      // address of the `in` or `out` instruction
+    pub expected: Event,
+    pub actual: Option<u16>, // value printed by the program, None if it reads
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "replay diverged at event #{} by instruction at {:05} (0x{:04x}): expected {}, ",
+            self.index, self.ip, self.ip, self.expected
+        )?;
+        match self.actual {
+            Some(v) => write!(f, "got {}", Event::Out(v)),
+            None => write!(f, "got in"),
+        }
+    }
+}
+
+/// Session being replayed and the position of the next event.
+#[derive(Debug)]
+pub(crate) struct Replay {
+    pub session: Session,
+    pub pos: usize,
+}
+
+impl Replay {
+    // Check the access of the instruction at `ip` against the next event,
+    // `output` is the value printed by `out` or None for `in`. Returns the
+    // character to read for `in`.
+    pub(crate) fn next(&mut self, ip: u16, output: Option<u16>) -> Result<Option<u8>, StopReason> {
+        let Some(&expected) = self.session.events.get(self.pos) else {
+            return Err(StopReason::ReplayFinished);
+        };
+        let c = match (expected, output) {
+            (Event::In(c), None) => Some(c),
+            (Event::Out(v), Some(actual)) if v == actual => None,
+            _ => {
+                return Err(StopReason::Diverged(Divergence {
+                    index: self.pos,
+                    ip,
+                    expected,
+                    actual: output,
+                }));
+            }
+        };
+        self.pos += 1;
+        Ok(c)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::testing::{R0, load};
+    use crate::emulator::{BufferOutput, Cpu, QueueInput};
+
+    // in r0; out r0; out <last>; halt
+    fn echo(last: char) -> Cpu {
+        let mut cpu = load(&[20, R0, 19, R0, 19, last as u16, 0]);
+        cpu.set_input(Box::new(QueueInput::new()));
+        cpu.set_output(Box::new(BufferOutput::new()));
+        cpu
+    }
+
+    fn record(cpu: &mut Cpu, line: &str) -> Session {
+        let mut input = QueueInput::new();
+        input.push(line);
+        cpu.set_input(Box::new(input));
+        cpu.start_recording();
+        assert_eq!(cpu.cont(), StopReason::Halted);
+        cpu.stop_recording().unwrap()
+    }
+
+    #[test]
+    fn record_the_exchanged_characters() {
+        let session = record(&mut echo('x'), "a");
+        let expected = [
+            Event::In(b'a'),
+            Event::Out(b'a' as u16),
+            Event::Out('x' as u16),
+        ];
+        assert_eq!(session.events(), expected);
+    }
+
+    #[test]
+    fn write_and_read() {
+        let session = record(&mut echo('x'), "a");
+        let mut buf = vec![];
+        session.write(&mut buf).unwrap();
+        assert_eq!(Session::read(&buf[..]).unwrap(), session);
+        assert!(Session::read(&b"in 1\n"[..]).is_err());
+    }
+
+    #[test]
+    fn replay_reports_the_divergence() {
+        let session = record(&mut echo('x'), "a");
+
+        let mut cpu = echo('x');
+        cpu.replay(session.clone());
+        assert_eq!(cpu.cont(), StopReason::Halted);
+        assert_eq!(cpu.replay_progress(), Some((3, 3)));
+
+        let mut cpu = echo('y');
+        cpu.replay(session);
+        let expected = Divergence {
+            index: 2,
+            ip: 4,
+            expected: Event::Out('x' as u16),
+            actual: Some('y' as u16),
+        };
+        assert_eq!(cpu.cont(), StopReason::Diverged(expected));
+        assert_eq!(cpu.ip, 4);
+    }
+
+    #[test]
+    fn new_sessions_are_not_reverted() {
+        let session = record(&mut echo('x'), "a");
+
+        let mut cpu = echo('x');
+        cpu.replay(session.clone());
+        cpu.cont_for(2);
+        cpu.replay(session);
+        assert_eq!(cpu.reverse_step(), StopReason::HistoryStart);
+        assert_eq!(cpu.replay_progress(), Some((0, 3)));
+
+        let mut input = QueueInput::new();
+        input.push("a");
+        cpu.set_input(Box::new(input));
+        cpu.stop_replay();
+        cpu.ip = 0;
+        cpu.start_recording();
+        cpu.cont_for(2);
+        cpu.start_recording();
+        cpu.step();
+        assert_eq!(cpu.reverse_step(), StopReason::StepLimit);
+        assert_eq!(cpu.reverse_step(), StopReason::HistoryStart);
+        assert!(cpu.recording().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,326 @@
+//! Save and restore the full state of the machine.
+//!
+//! A snapshot starts with a magic string and a format version followed by the
+//! state, all numbers are stored in little endian:
+//!   - `ip`, `footprint` and the registers as u16
+//!   - the whole memory as u16
+//!   - the stack: a u32 length followed by the u16 values
+//!   - the pending input: a u32 length followed by the bytes
+//!   - the shadow call stack: a u32 length followed by the frames
+//!   - the breakpoints: the next id and a u32 length followed by the
+//!     breakpoints, conditions are stored as their source
+//!
+//! The input source itself is not saved, the restored CPU keeps its own. The
+//! history used for reverse execution and the recorded or replayed sessions are
+//! not saved either.
+
+use crate::emulator::breakpoint::Breakpoint;
+use crate::emulator::expr::Expr;
+use crate::emulator::{Cpu, Frame, layout};
+use std::io::{self, Read, Write};
+
+const MAGIC: &[u8; 8] = b"SYNACOR\0";
+const VERSION: u16 = 1;
+
+impl Cpu {
+    /// Write a snapshot of the machine to `out`.
+    pub fn save(&self, out: &mut impl Write) -> io::Result<()> {
+        out.write_all(MAGIC)?;
+        write_u16(out, VERSION)?;
+
+        write_u16(out, self.ip)?;
+        write_u16(out, self.footprint)?;
+        for reg in self.regs {
+            write_u16(out, reg)?;
+        }
+        for word in self.mem {
+            write_u16(out, word)?;
+        }
+
+        write_len(out, self.stack.len())?;
+        for value in &self.stack {
+            write_u16(out, *value)?;
+        }
+
+        write_len(out, self.input_buf.len())?;
+        for byte in &self.input_buf {
+            out.write_all(&[*byte])?;
+        }
+
+        let frames = self.call_stack.frames();
+        write_len(out, frames.len())?;
+        for frame in frames {
+            write_u16(out, frame.call_site)?;
+            write_u16(out, frame.target)?;
+            write_u16(out, frame.ret)?;
+            write_len(out, frame.sp)?;
+        }
+
+        write_len(out, self.breakpoints.next_id)?;
+        write_len(out, self.breakpoints.list.len())?;
+        for bp in &self.breakpoints.list {
+            write_len(out, bp.id)?;
+            write_u16(out, bp.addr)?;
+            out.write_all(&[bp.enabled as u8])?;
+            write_u64(out, bp.hits)?;
+            write_u64(out, bp.ignore)?;
+            write_opt_str(out, bp.name.as_deref())?;
+            write_opt_str(out, bp.condition.as_ref().map(|c| c.to_string()).as_deref())?;
+        }
+
+        Ok(())
+    }
+
+    /// Replace the state of the machine by the snapshot read from `input`.
+    /// Nothing is modified if the snapshot is invalid.
+    pub fn restore(&mut self, input: &mut impl Read) -> io::Result<()> {
+        let mut magic = [0; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a snapshot"));
+        }
+        let version = read_u16(input)?;
+        if version != VERSION {
+            return Err(invalid(&format!("unsupported snapshot version {version}")));
+        }
+
+        let ip = read_u16(input)?;
+        let footprint = read_u16(input)?;
+        if footprint > layout::MEM_SIZE {
+            return Err(invalid(&format!("footprint {footprint} is too large")));
+        }
+        let mut regs = [0; layout::NUM_REGS as usize];
+        for reg in regs.iter_mut() {
+            *reg = read_u16(input)?;
+        }
+        let mut mem = [0; layout::MEM_SIZE as usize];
+        for word in mem.iter_mut() {
+            *word = read_u16(input)?;
+        }
+
+        let len = read_len(input)?;
+        let stack = (0..len)
+            .map(|_| read_u16(input))
+            .collect::<io::Result<Vec<u16>>>()?;
+
+        let len = read_len(input)?;
+        let input_buf = read_bytes(input, len)?;
+
+        let len = read_len(input)?;
+        let mut frames = Vec::with_capacity(len.min(1024));
+        for _ in 0..len {
+            frames.push(Frame {
+                call_site: read_u16(input)?,
+                target: read_u16(input)?,
+                ret: read_u16(input)?,
+                sp: read_len(input)?,
+            });
+        }
+
+        let next_id = read_len(input)?;
+        let len = read_len(input)?;
+        let mut breakpoints = Vec::with_capacity(len.min(1024));
+        for _ in 0..len {
+            let id = read_len(input)?;
+            let addr = read_u16(input)?;
+            let mut enabled = [0];
+            input.read_exact(&mut enabled)?;
+            let hits = read_u64(input)?;
+            let ignore = read_u64(input)?;
+            let name = read_opt_str(input)?;
+            let condition = read_opt_str(input)?
+                .map(|src| Expr::parse(&src))
+                .transpose()
+                .map_err(|e| invalid(&format!("invalid breakpoint condition: {e}")))?;
+            breakpoints.push(Breakpoint {
+                id,
+                addr,
+                name,
+                enabled: enabled[0] != 0,
+                hits,
+                condition,
+                ignore,
+            });
+        }
+
+        if breakpoints.iter().any(|bp| bp.id >= next_id) {
+            return Err(invalid(&format!("breakpoint ids are not below {next_id}")));
+        }
+
+        self.ip = ip;
+        self.footprint = footprint;
+        self.regs = regs;
+        self.mem = mem;
+        self.invalidate_decoded();
+        self.stack = stack;
+        self.input_buf = input_buf.into();
+        self.call_stack.clear();
+        for frame in frames {
+            self.call_stack.push(frame);
+        }
+        self.history.clear();
+        self.breakpoints.next_id = next_id;
+        self.breakpoints.list = breakpoints;
+        Ok(())
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn write_u16(out: &mut impl Write, value: u16) -> io::Result<()> {
+    out.write_all(&value.to_le_bytes())
+}
+
+fn write_u64(out: &mut impl Write, value: u64) -> io::Result<()> {
+    out.write_all(&value.to_le_bytes())
+}
+
+// Lengths and ids are stored on 32 bits
+fn write_len(out: &mut impl Write, len: usize) -> io::Result<()> {
+    let len = u32::try_from(len).map_err(|_| invalid("length doesn't fit in 32 bits"))?;
+    out.write_all(&len.to_le_bytes())
+}
+
+// An optional string is a presence byte followed by the length and the bytes
+fn write_opt_str(out: &mut impl Write, s: Option<&str>) -> io::Result<()> {
+    match s {
+        None => out.write_all(&[0]),
+        Some(s) => {
+            out.write_all(&[1])?;
+            write_len(out, s.len())?;
+            out.write_all(s.as_bytes())
+        }
+    }
+}
+
+fn read_u16(input: &mut impl Read) -> io::Result<u16> {
+    let mut buf = [0; 2];
+    input.read_exact(&mut buf)?;
+    Ok(u16::from_le_bytes(buf))
+}
+
+fn read_u64(input: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    input.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_len(input: &mut impl Read) -> io::Result<usize> {
+    let mut buf = [0; 4];
+    input.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf) as usize)
+}
+
+// The length is checked against the data that is actually read, so a corrupted
+// length doesn't allocate a huge buffer.
+fn read_bytes(input: &mut impl Read, len: usize) -> io::Result<Vec<u8>> {
+    let mut buf = vec![];
+    input.take(len as u64).read_to_end(&mut buf)?;
+    if buf.len() != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(buf)
+}
+
+fn read_opt_str(input: &mut impl Read) -> io::Result<Option<String>> {
+    let mut present = [0];
+    input.read_exact(&mut present)?;
+    if present[0] == 0 {
+        return Ok(None);
+    }
+    let len = read_len(input)?;
+    String::from_utf8(read_bytes(input, len)?)
+        .map(Some)
+        .map_err(|_| invalid("invalid string"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::testing::{MINUS_ONE, R0, R1};
+    use crate::emulator::{BufferOutput, StopReason, testing};
+
+    // Print the letters from 'J' down to 'A', one call per letter
+    #[rustfmt::skip]
+    const PROGRAM: &[u16] = &[
+        1, R0, 10,            // 0: set r0 10
+        17, 9,                // 3: call 9
+        7, R0, 3,             // 5: jt r0 3
+        0,                    // 8: halt
+        9, R1, R0, 64,        // 9: add r1 r0 64
+        19, R1,               // 13: out r1
+        9, R0, R0, MINUS_ONE, // 15: add r0 r0 -1
+        18,                   // 19: ret
+    ];
+
+    fn load(words: &[u16]) -> (Cpu, BufferOutput) {
+        let mut cpu = testing::load(words);
+        let output = BufferOutput::new();
+        cpu.set_output(Box::new(output.clone()));
+        (cpu, output)
+    }
+
+    fn snapshot(cpu: &Cpu) -> Vec<u8> {
+        let mut buf = vec![];
+        cpu.save(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn restored_machine_continues_the_same() {
+        let (mut cpu, output) = load(PROGRAM);
+        cpu.breakpoints.add(8, Some("end")).unwrap();
+        cpu.breakpoints.get_mut(0).unwrap().enabled = false;
+        cpu.cont_for(5);
+        assert_eq!(cpu.call_stack.frames().len(), 1);
+        let buf = snapshot(&cpu);
+
+        assert_eq!(cpu.cont(), StopReason::Halted);
+        assert_eq!(output.take(), "JIHGFEDCBA");
+
+        let (mut restored, restored_output) = load(&[]);
+        restored.restore(&mut &buf[..]).unwrap();
+        assert_eq!(restored.footprint, PROGRAM.len() as u16);
+        assert_eq!(restored.call_stack.frames().len(), 1);
+        assert_eq!(restored.breakpoints.iter().count(), 1);
+        assert_eq!(restored.breakpoints.add(100, None).unwrap(), 1);
+
+        assert_eq!(restored.cont(), StopReason::Halted);
+        assert_eq!(restored_output.take(), "IHGFEDCBA");
+        assert_eq!((restored.ip, restored.regs), (cpu.ip, cpu.regs));
+        assert_eq!(restored.stack, cpu.stack);
+    }
+
+    #[test]
+    fn invalid_snapshots_are_rejected() {
+        let (mut cpu, _) = load(PROGRAM);
+        cpu.cont_for(3);
+        let restore = |buf: &[u8]| {
+            let (mut target, _) = load(&[]);
+            let err = target.restore(&mut &buf[..]).unwrap_err();
+            assert_eq!(target.ip, 0);
+            assert_eq!(target.footprint, 0);
+            err
+        };
+
+        let mut buf = snapshot(&cpu);
+        buf[0] = b'X';
+        assert_eq!(restore(&buf).to_string(), "not a snapshot");
+
+        let buf = snapshot(&cpu);
+        let err = restore(&buf[..buf.len() - 1]);
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        cpu.footprint = 0xffff;
+        let err = restore(&snapshot(&cpu));
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        cpu.footprint = PROGRAM.len() as u16;
+
+        cpu.breakpoints.add(8, None).unwrap();
+        cpu.breakpoints.next_id = 0;
+        let err = restore(&snapshot(&cpu));
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,51 @@
+use crate::emulator::{Divergence, VmError, WatchHit};
+use std::fmt;
+
+/// Why the execution stopped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StopReason {
+    /// The program executed `halt`, or `ret` with an empty stack.
+    Halted,
+    /// The instruction pointer reached the breakpoint at the given address.
+    Breakpoint(u16),
+    /// An `in` instruction has no input to read. It is executed again when
+    /// the execution is resumed.
+    WaitingForInput,
+    /// The instruction failed, the instruction pointer is left on it.
+    Error(VmError),
+    /// The requested number of instructions has been executed.
+    StepLimit,
+    /// The address requested by `next`, `finish` or `until` has been reached.
+    Reached(u16),
+    /// An instruction accessed a watched address. The instruction has been
+    /// executed.
+    Watchpoint(WatchHit),
+    /// Reverse execution reached the oldest instruction of the history.
+    HistoryStart,
+    /// All the events of the replayed session have been consumed. The `in`
+    /// or `out` instruction that needs the next one is executed normally
+    /// when the execution is resumed.
+    ReplayFinished,
+    /// The program didn't behave as recorded in the replayed session, the
+    /// instruction pointer is left on the `in` or `out` instruction.
+    Diverged(Divergence),
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StopReason::Halted => write!(f, "CPU halted"),
+            StopReason::Breakpoint(addr) => {
+                write!(f, "reached breakpoint at {addr:05} (0x{addr:04x})")
+            }
+            StopReason::WaitingForInput => write!(f, "waiting for input"),
+            StopReason::Error(e) => write!(f, "CPU error: {e}"),
+            StopReason::StepLimit => write!(f, "step limit reached"),
+            StopReason::Reached(addr) => write!(f, "stopped at {addr:05} (0x{addr:04x})"),
+            StopReason::Watchpoint(hit) => write!(f, "{hit}"),
+            StopReason::HistoryStart => write!(f, "reached the start of the history"),
+            StopReason::ReplayFinished => write!(f, "replay finished"),
+            StopReason::Diverged(d) => write!(f, "{d}"),
+        }
+    }
+}
@@ -0,0 +1,132 @@
+use std::fmt;
+
+use crate::emulator::disasm::printable;
+
+/// Text found in memory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FoundString {
+    pub addr: u16, // address of the first character, or of the length
+    /// True if the characters are preceded by their number.
+    pub prefixed: bool,
+    pub text: String,
+}
+
+impl fmt::Display for FoundString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = if self.prefixed {
+            "prefixed"
+        } else {
+            "printable"
+        };
+        write!(
+            f,
+            "{:05} (0x{:04x}) {kind:<9} {:>4} \"{}\"",
+            self.addr,
+            self.addr,
+            self.text.chars().count(),
+            self.text.escape_default()
+        )
+    }
+}
+
+/// Scan the first `len` words of `mem` for strings of at least `min`
+/// characters: a length followed by as many printable characters, or runs of
+/// printable characters. Obfuscated strings are only visible once decoded, see
+/// [`StringCapture`].
+pub fn scan(mem: &[u16], len: u16, min: usize) -> Vec<FoundString> {
+    let words = &mem[..len as usize];
+    let text = |words: &[u16]| words.iter().filter_map(|w| printable(*w)).collect();
+    let mut found = vec![];
+    let mut addr = 0;
+    while addr < words.len() {
+        let count = words[addr] as usize;
+        let chars = words.get(addr + 1..addr + 1 + count).unwrap_or(&[]);
+        if count >= min && chars.len() == count && chars.iter().all(|w| printable(*w).is_some()) {
+            found.push(FoundString {
+                addr: addr as u16,
+                prefixed: true,
+                text: text(chars),
+            });
+            addr += 1 + count;
+            continue;
+        }
+        let run = words[addr..]
+            .iter()
+            .take_while(|w| printable(**w).is_some())
+            .count();
+        if run >= min {
+            found.push(FoundString {
+                addr: addr as u16,
+                prefixed: false,
+                text: text(&words[addr..addr + run]),
+            });
+            addr += run;
+        } else {
+            addr += 1;
+        }
+    }
+    found
+}
+
+/// Line printed by the program.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CapturedString {
+    pub text: String,
+    /// Inclusive range of the memory read for the characters, if any.
+    pub source: Option<(u16, u16)>,
+}
+
+impl fmt::Display for CapturedString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.source {
+            Some((start, end)) => write!(f, "0x{start:04x}..0x{end:04x}")?,
+            None => write!(f, "{:14}", "-")?,
+        }
+        write!(f, " \"{}\"", self.text.escape_default())
+    }
+}
+
+/// Capture the lines printed by `out` while the program runs.
+///
+/// The source of a character printed from a register is the last memory word
+/// read by `rmem` before it, which follows the decoding of strings that are
+/// not stored as plain text.
+#[derive(Debug, Default)]
+pub struct StringCapture {
+    strings: Vec<CapturedString>,
+    current: CapturedString,
+    last_read: Option<u16>,
+}
+
+impl StringCapture {
+    pub fn new() -> StringCapture {
+        StringCapture::default()
+    }
+
+    /// Lines printed so far, the one not terminated yet is left out.
+    pub fn strings(&self) -> &[CapturedString] {
+        &self.strings
+    }
+
+    pub(crate) fn on_read(&mut self, addr: u16) {
+        self.last_read = Some(addr);
+    }
+
+    // `literal` is true if the character is an operand of `out`, it was not
+    // read from memory.
+    pub(crate) fn on_out(&mut self, c: char, literal: bool) {
+        let read = self.last_read.take();
+        if let Some(addr) = read.filter(|_| !literal) {
+            let source = &mut self.current.source;
+            *source = Some(match *source {
+                Some((start, end)) => (start.min(addr), end.max(addr)),
+                None => (addr, addr),
+            });
+        }
+        if c == '\n' {
+            self.strings.push(std::mem::take(&mut self.current));
+        } else {
+            self.current.text.push(c);
+        }
+    }
+}
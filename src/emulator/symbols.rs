@@ -0,0 +1,59 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+
+/// Names given to memory addresses.
+#[derive(Debug, Default, Clone)]
+pub struct Symbols {
+    names: BTreeMap<u16, String>,
+}
+
+impl Symbols {
+    /// Load symbols from a file. Each line is an address, in decimal or in
+    /// hexadecimal with the 0x prefix, followed by its name. Empty lines and
+    /// lines starting with `#` are ignored.
+    pub fn load(filename: &str) -> io::Result<Symbols> {
+        let mut symbols = Symbols::default();
+        for (idx, line) in fs::read_to_string(filename)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{filename}:{}: expected '<addr> <name>'", idx + 1),
+                )
+            };
+            let (addr, name) = line.split_once(char::is_whitespace).ok_or_else(invalid)?;
+            let addr = match addr.strip_prefix("0x") {
+                Some(hex) => u16::from_str_radix(hex, 16),
+                None => addr.parse::<u16>(),
+            }
+            .map_err(|_| invalid())?;
+            symbols.insert(addr, name.trim());
+        }
+        Ok(symbols)
+    }
+
+    pub fn insert(&mut self, addr: u16, name: &str) {
+        self.names.insert(addr, name.to_string());
+    }
+
+    pub fn remove(&mut self, addr: u16) -> Option<String> {
+        self.names.remove(&addr)
+    }
+
+    pub fn get(&self, addr: u16) -> Option<&str> {
+        self.names.get(&addr).map(String::as_str)
+    }
+
+    /// Iterate over symbols sorted by address.
+    pub fn iter(&self) -> impl Iterator<Item = (u16, &str)> {
+        self.names.iter().map(|(addr, name)| (*addr, name.as_str()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+}
@@ -0,0 +1,130 @@
+use std::fmt::Write as _;
+use std::io::{self, Write};
+
+use crate::emulator::{Insn, layout};
+
+/// Format of the trace records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// One aligned line per instruction, meant to be read and diffed.
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+/// Write a record for each executed instruction.
+///
+/// Steps are counted for all the instructions, including the ones outside of
+/// the traced ranges, so the records of two runs can be compared.
+pub struct Tracer {
+    out: Box<dyn Write>,
+    format: TraceFormat,
+    ranges: Vec<(u16, u16)>, // inclusive, everything is traced if empty
+    steps: u64,
+}
+
+impl Tracer {
+    pub fn new(out: Box<dyn Write>, format: TraceFormat) -> Tracer {
+        Tracer {
+            out,
+            format,
+            ranges: vec![],
+            steps: 0,
+        }
+    }
+
+    /// Only trace the instructions between `start` and `end` included. Can be
+    /// called several times to trace several ranges.
+    pub fn add_range(&mut self, start: u16, end: u16) {
+        self.ranges.push((start, end));
+    }
+
+    pub fn format(&self) -> TraceFormat {
+        self.format
+    }
+
+    pub fn ranges(&self) -> &[(u16, u16)] {
+        &self.ranges
+    }
+
+    /// Number of instructions executed since the tracer was created.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    // Called once the instruction `insn` at `ip` has been executed. Errors
+    // are ignored so a full disk doesn't stop the program.
+    pub(crate) fn record(
+        &mut self,
+        ip: u16,
+        insn: &Insn,
+        before: &[u16; layout::NUM_REGS as usize],
+        after: &[u16; layout::NUM_REGS as usize],
+        stack_depth: usize,
+    ) {
+        self.steps += 1;
+        if !self.ranges.is_empty()
+            && !self
+                .ranges
+                .iter()
+                .any(|(start, end)| (*start..=*end).contains(&ip))
+        {
+            return;
+        }
+
+        let operands = insn.operands();
+        // Operands resolved with the registers as they were before executing
+        let values: Vec<u16> = operands
+            .iter()
+            .map(|&v| {
+                if layout::is_reg(v) {
+                    before[(v - layout::REG_MIN) as usize]
+                } else {
+                    v
+                }
+            })
+            .collect();
+
+        let line = match self.format {
+            TraceFormat::Text => {
+                let mut line =
+                    format!("{:>10} {ip:05} (0x{ip:04x}) {:<4}", self.steps, insn.name());
+                for (op, value) in operands.iter().zip(&values) {
+                    if layout::is_reg(*op) {
+                        let _ = write!(line, " r{}={value:04x}", op - layout::REG_MIN);
+                    } else {
+                        let _ = write!(line, " {value:04x}");
+                    }
+                }
+                format!(
+                    "{line:<54} | regs {} -> {} | stack {stack_depth}",
+                    hex_words(before),
+                    hex_words(after)
+                )
+            }
+            TraceFormat::Json => format!(
+                "{{\"step\":{},\"ip\":{ip},\"insn\":\"{}\",\"operands\":{:?},\"values\":{:?},\
+                 \"regs_before\":{:?},\"regs_after\":{:?},\"stack_depth\":{stack_depth}}}",
+                self.steps,
+                insn.name(),
+                operands,
+                values,
+                before,
+                after
+            ),
+        };
+        let _ = writeln!(self.out, "{line}");
+    }
+}
+
+fn hex_words(words: &[u16]) -> String {
+    words
+        .iter()
+        .map(|w| format!("{w:04x}"))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
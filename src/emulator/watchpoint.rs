@@ -0,0 +1,218 @@
+use crate::emulator::{VmError, layout};
+use std::fmt;
+
+/// Kind of access that triggers a watchpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    /// A write that modifies the value.
+    Change,
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Access::Read => f.pad("read"),
+            Access::Write => f.pad("write"),
+            Access::Change => f.pad("change"),
+        }
+    }
+}
+
+/// Watched addresses, they are given using the layout of the architecture so
+/// registers are the addresses from `REG_MIN` to `REG_MAX`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Target {
+    pub start: u16,
+    pub end: u16, // inclusive
+}
+
+impl Target {
+    pub fn new(start: u16, end: u16) -> Result<Target, VmError> {
+        // A range can't mix memory and registers
+        let valid = (layout::is_mem(start) && layout::is_mem(end))
+            || (layout::is_reg(start) && layout::is_reg(end));
+        if !valid {
+            return Err(VmError::InvalidAddress(end));
+        }
+        if start > end {
+            return Err(VmError::InvalidAddress(start));
+        }
+        Ok(Target { start, end })
+    }
+
+    pub fn contains(&self, addr: u16) -> bool {
+        (self.start..=self.end).contains(&addr)
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if layout::is_reg(self.start) {
+            write!(f, "r{}", self.start - layout::REG_MIN)?;
+            if self.end != self.start {
+                write!(f, "..r{}", self.end - layout::REG_MIN)?;
+            }
+            Ok(())
+        } else if self.end != self.start {
+            write!(f, "mem[0x{:04x}..0x{:04x}]", self.start, self.end)
+        } else {
+            write!(f, "mem[0x{:04x}]", self.start)
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Watchpoint {
+    pub id: usize,
+    pub target: Target,
+    pub access: Access,
+    pub enabled: bool,
+    pub hits: u64,
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "#{:<3} {:<6} {} {} hits: {}",
+            self.id,
+            self.access,
+            self.target,
+            if self.enabled { "enabled" } else { "disabled" },
+            self.hits
+        )
+    }
+}
+
+/// Details of the access that triggered a watchpoint.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatchHit {
+    pub id: usize,
+    pub access: Access,
+    pub ip: u16,   // address of the instruction doing the access
+    pub addr: u16, // accessed address
+    pub old: u16,
+    pub new: u16,
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let target = Target {
+            start: self.addr,
+            end: self.addr,
+        };
+        write!(
+            f,
+            "watchpoint #{} ({}) {target} by instruction at {:05} (0x{:04x}): ",
+            self.id, self.access, self.ip, self.ip
+        )?;
+        if self.access == Access::Read {
+            write!(f, "value 0x{:04x}", self.new)
+        } else {
+            write!(f, "old 0x{:04x}, new 0x{:04x}", self.old, self.new)
+        }
+    }
+}
+
+/// Table of watchpoints. Like breakpoints, ids are never reused.
+#[derive(Debug, Default)]
+pub struct Watchpoints {
+    list: Vec<Watchpoint>,
+    next_id: usize,
+    hit: Option<WatchHit>, // first watchpoint triggered by the current instruction
+}
+
+impl Watchpoints {
+    /// Add a watchpoint and return its id.
+    pub fn add(&mut self, target: Target, access: Access) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.list.push(Watchpoint {
+            id,
+            target,
+            access,
+            enabled: true,
+            hits: 0,
+        });
+        id
+    }
+
+    /// Remove the watchpoint `id` and return it.
+    pub fn delete(&mut self, id: usize) -> Option<Watchpoint> {
+        let idx = self.list.iter().position(|wp| wp.id == id)?;
+        Some(self.list.remove(idx))
+    }
+
+    pub fn get_mut(&mut self, id: usize) -> Option<&mut Watchpoint> {
+        self.list.iter_mut().find(|wp| wp.id == id)
+    }
+
+    /// Iterate over watchpoints in the order they were added.
+    pub fn iter(&self) -> impl Iterator<Item = &Watchpoint> {
+        self.list.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    // Called when the value at `addr` is read by an instruction.
+    pub(crate) fn on_read(&mut self, addr: u16, value: u16) {
+        self.check(addr, value, value, |access| access == Access::Read);
+    }
+
+    // Called when the value at `addr` is written by an instruction.
+    pub(crate) fn on_write(&mut self, addr: u16, old: u16, new: u16) {
+        self.check(addr, old, new, |access| {
+            access == Access::Write || (access == Access::Change && old != new)
+        });
+    }
+
+    // Take the watchpoint triggered by the last instruction, `ip` being its
+    // address.
+    pub(crate) fn take_hit(&mut self, ip: u16) -> Option<WatchHit> {
+        let mut hit = self.hit.take()?;
+        hit.ip = ip;
+        Some(hit)
+    }
+
+    // Returns the first enabled watchpoint triggered by the instruction at `ip`
+    // writing `new` over `old` at `addr`. Unlike `on_write` nothing is updated.
+    pub(crate) fn find_write(&self, addr: u16, old: u16, new: u16, ip: u16) -> Option<WatchHit> {
+        self.list
+            .iter()
+            .find(|wp| {
+                wp.enabled
+                    && wp.target.contains(addr)
+                    && (wp.access == Access::Write || (wp.access == Access::Change && old != new))
+            })
+            .map(|wp| WatchHit {
+                id: wp.id,
+                access: wp.access,
+                ip,
+                addr,
+                old,
+                new,
+            })
+    }
+
+    fn check(&mut self, addr: u16, old: u16, new: u16, matches: impl Fn(Access) -> bool) {
+        for wp in self.list.iter_mut() {
+            if wp.enabled && wp.target.contains(addr) && matches(wp.access) {
+                wp.hits += 1;
+                if self.hit.is_none() {
+                    self.hit = Some(WatchHit {
+                        id: wp.id,
+                        access: wp.access,
+                        ip: 0, // set when the hit is taken
+                        addr,
+                        old,
+                        new,
+                    });
+                }
+            }
+        }
+    }
+}
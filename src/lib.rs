@@ -0,0 +1,18 @@
+//! Virtual machine for the Synacor Challenge.
+//!
+//! The [`Cpu`] loads a ROM and executes it one instruction at a time with
+//! [`Cpu::step`], or until something stops it with [`Cpu::cont`] and
+//! [`Cpu::run`]. They all return a [`StopReason`] telling why the execution
+//! stopped. Errors are reported as [`VmError`] and leave the CPU in a state
+//! that can still be inspected.
+
+pub mod emulator;
+
+pub use emulator::{
+    Access, BasicBlock, Breakpoint, Breakpoints, BufferOutput, CallStack, CapturedString, Cfg,
+    ChainInput, Coverage, Cpu, Disassembly, Divergence, Edge, Env, Event, Expr, ExprError,
+    FileInput, FileOutput, FnStats, FoundString, Frame, History, Input, Insn, Mismatch, Output,
+    Profiler, QueueInput, Session, StdinInput, StopReason, StringCapture, Symbols, Target,
+    TeeOutput, TerminalOutput, TraceFormat, Tracer, VmError, WatchHit, Watchpoint, Watchpoints,
+    Xref, layout,
+};
@@ -1,8 +1,19 @@
 use std::fs::File;
-use std::io::{self, Read, Write};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+
+use synacor::{
+    Access, BufferOutput, Coverage, Cpu, Expr, FileInput, FileOutput, Profiler, Session,
+    StopReason, StringCapture, Symbols, Target, TeeOutput, TerminalOutput, TraceFormat, Tracer,
+    layout,
+};
 
 mod args;
-mod emulator;
+
+// Number of entries shown in each section of the profile
+const PROFILE_LIMIT: usize = 20;
+
+// Minimum number of characters of the strings found in memory
+const STRINGS_MIN: usize = 4;
 
 fn main() -> io::Result<()> {
     let args = args::read_args();
@@ -11,45 +22,153 @@ fn main() -> io::Result<()> {
     let mut data = vec![];
     f.read_to_end(&mut data)?;
 
-    let mut cpu = emulator::Cpu::load(data);
+    let mut cpu = match Cpu::load(data) {
+        Ok(cpu) => cpu,
+        Err(e) => {
+            println!("Failed to load {}: {e}", &args.filename);
+            std::process::exit(1)
+        }
+    };
     println!(
         "{} words loaded in memory from {}",
         cpu.footprint, &args.filename
     );
 
     if args.disassemble {
-        cpu.disassemble();
+        cpu.disassemble(&mut io::stdout(), args.raw)?;
         println!("--- Done ---");
         std::process::exit(0);
     }
 
-    if let Some(bp) = args.breakpoint {
-        cpu.set_breakpoint(bp);
+    if args.cfg.is_some() || args.call_graph.is_some() {
+        if let Some((entry, fname)) = &args.cfg {
+            let cfg = cpu.cfg(*entry);
+            let mut out = BufWriter::new(File::create(fname)?);
+            cfg.write_dot(&mut out)?;
+            out.flush()?;
+            println!("{} basic block(s) written to {fname}", cfg.blocks.len());
+        }
+        if let Some(fname) = &args.call_graph {
+            let mut out = BufWriter::new(File::create(fname)?);
+            cpu.call_graph(&mut out)?;
+            out.flush()?;
+            println!("Call graph written to {fname}");
+        }
+        std::process::exit(0);
+    }
+
+    let mut symbols = match &args.symbols {
+        Some(fname) => Symbols::load(fname)?,
+        None => Symbols::default(),
+    };
+
+    if let Some(limit) = args.history {
+        cpu.history.set_limit(limit);
+    }
+
+    if let Some(fname) = &args.restore {
+        restore_snapshot(&mut cpu, fname)?;
+        println!("Snapshot restored from {fname}");
+    }
+
+    // The debugger keeps a handle on the terminal to not mix its messages with
+    // a line started by the program, and on the buffer to show captured output
+    let terminal = TerminalOutput::new();
+    let buffer = BufferOutput::new();
+    let mut output_mode = String::from("terminal");
+    // Session of the last stopped recording, kept to be saved
+    let mut last_session = None;
+    cpu.set_output(Box::new(terminal.clone()));
+
+    if let Some(fname) = &args.trace {
+        let mut tracer = Tracer::new(
+            Box::new(BufWriter::new(File::create(fname)?)),
+            args.trace_format,
+        );
+        for (start, end) in &args.trace_ranges {
+            tracer.add_range(*start, *end);
+        }
+        cpu.tracer = Some(tracer);
+    } else if args.verbose {
+        cpu.tracer = Some(Tracer::new(Box::new(io::stdout()), TraceFormat::Text));
+    }
+
+    if args.coverage.is_some() {
+        cpu.coverage = Some(Coverage::new());
+    }
+
+    if args.profile {
+        cpu.profiler = Some(Profiler::new());
+    }
+
+    if let Some(fname) = &args.input {
+        cpu.feed(Box::new(FileInput::open(fname)?));
+    }
+
+    if args.record.is_some() {
+        cpu.start_recording();
+    }
+
+    if let Some(fname) = &args.replay {
+        cpu.replay(Session::load(fname)?);
+        println!("Replaying {fname}");
+    }
+
+    for bp in args.breakpoints {
+        set_breakpoint(&mut cpu, bp, None, None);
     }
 
     // Enter debug mode by default
     // TODO: use a parameter
 
-    println!("[b]reak, [c]ontinue, [p]rint, [q]uit, read, [r]un, [s]tep");
+    println!("[b]reak, [c]ontinue, [p]rint, [q]uit, read, [r]un, [s]tep [N]");
+    println!("[n]ext, finish, until <addr>");
+    println!("reverse: [rs] reverse-step, [rc] reverse-continue, history [limit]");
+    println!("breakpoints: [bl]ist, delete, enable, disable, condition, ignore, eval");
+    println!("watchpoints: watch, [wl]ist, unwatch");
+    println!("[bt] backtrace, symbol <addr> [name], symbols");
+    println!("snapshots: save <file>, load <file>");
+    println!("input: feed <file>");
+    println!("output: output [terminal|log <file>|file <file>|capture|show]");
+    println!("coverage: coverage [on|off|reset|save <file>]");
+    println!("disassembly: disassemble [file], follows the executed code with coverage on");
+    println!("profile: profile [on|off|reset|<count>]");
+    println!("strings: strings [<min>|on|off|reset|show]");
+    println!("trace: trace [<file> [text|json] [start..end]...|off]");
+    println!("sessions: record [stop|save <file>], replay [<file>|stop]");
     loop {
+        terminal.end_line();
         print!("debug> ");
         io::stdout().flush().unwrap();
 
         let mut input = String::new();
 
-        io::stdin()
+        if io::stdin()
             .read_line(&mut input)
-            .expect("failed to read input");
+            .expect("failed to read input")
+            == 0
+        {
+            // End of input
+            break;
+        }
 
         let input = input.trim();
         let mut parts = input.split_whitespace();
 
         match parts.next() {
             Some("b") | Some("break") => {
+                // b <addr> [name] [if <condition>]
                 if let Some(arg) = parts.next() {
-                    match arg.parse::<u16>() {
-                        Ok(n) => cpu.set_breakpoint(n),
-                        Err(_) => {
+                    match parse_addr(arg) {
+                        Some(n) => {
+                            let rest: Vec<&str> = parts.collect();
+                            let (name, cond) = match rest.iter().position(|w| *w == "if") {
+                                Some(i) => (rest[..i].first(), Some(rest[i + 1..].join(" "))),
+                                None => (rest.first(), None),
+                            };
+                            set_breakpoint(&mut cpu, n, name.copied(), cond.as_deref());
+                        }
+                        None => {
                             println!("Invalid breakpoint, a valid memory integer is expected")
                         }
                     }
@@ -57,13 +176,331 @@ fn main() -> io::Result<()> {
                     println!("Missing breakpoint integer")
                 }
             }
-            Some("c") | Some("continue") => cpu.cont(args.verbose),
+            Some("bl") | Some("breakpoints") => {
+                if cpu.breakpoints.is_empty() {
+                    println!("No breakpoints");
+                }
+                for bp in cpu.breakpoints.iter() {
+                    println!("{bp}");
+                }
+            }
+            Some("delete") => match parse_id(parts.next()) {
+                Some(id) => match cpu.breakpoints.delete(id) {
+                    Some(bp) => println!("Deleted breakpoint {bp}"),
+                    None => println!("No breakpoint #{id}"),
+                },
+                None => println!("A breakpoint id is expected"),
+            },
+            Some(cmd @ ("enable" | "disable")) => match parse_id(parts.next()) {
+                Some(id) => match cpu.breakpoints.get_mut(id) {
+                    Some(bp) => {
+                        bp.enabled = cmd == "enable";
+                        println!("{bp}");
+                    }
+                    None => println!("No breakpoint #{id}"),
+                },
+                None => println!("A breakpoint id is expected"),
+            },
+            Some("condition") => match parse_id(parts.next()) {
+                Some(id) => {
+                    let src = parts.collect::<Vec<&str>>().join(" ");
+                    let cond = if src.is_empty() {
+                        Ok(None)
+                    } else {
+                        Expr::parse(&src).map(Some)
+                    };
+                    match (cpu.breakpoints.get_mut(id), cond) {
+                        (None, _) => println!("No breakpoint #{id}"),
+                        (_, Err(e)) => println!("Invalid condition: {e}"),
+                        (Some(bp), Ok(cond)) => {
+                            bp.condition = cond;
+                            println!("{bp}");
+                        }
+                    }
+                }
+                None => println!("A breakpoint id is expected"),
+            },
+            Some("ignore") => match (parse_id(parts.next()), parts.next()) {
+                (Some(id), Some(count)) => match (cpu.breakpoints.get_mut(id), count.parse()) {
+                    (None, _) => println!("No breakpoint #{id}"),
+                    (_, Err(_)) => println!("Invalid ignore count"),
+                    (Some(bp), Ok(count)) => {
+                        bp.ignore = count;
+                        println!("{bp}");
+                    }
+                },
+                _ => println!("A breakpoint id and a count are expected"),
+            },
+            Some("watch") => {
+                // watch <r0..r7|addr|start..end> [read|write|change]
+                let target = parts.next().map(parse_target);
+                let access = match parts.next() {
+                    None | Some("write") => Some(Access::Write),
+                    Some("read") => Some(Access::Read),
+                    Some("change") => Some(Access::Change),
+                    Some(_) => None,
+                };
+                match (target, access) {
+                    (None, _) => println!("A register, an address or a range is expected"),
+                    (Some(Err(e)), _) => println!("Invalid watchpoint: {e}"),
+                    (_, None) => println!("Access is one of read, write or change"),
+                    (Some(Ok(target)), Some(access)) => {
+                        let id = cpu.watchpoints.add(target, access);
+                        println!("Watchpoint #{id} set on {target} ({access})");
+                    }
+                }
+            }
+            Some("wl") | Some("watchpoints") => {
+                if cpu.watchpoints.is_empty() {
+                    println!("No watchpoints");
+                }
+                for wp in cpu.watchpoints.iter() {
+                    println!("{wp}");
+                }
+            }
+            Some("unwatch") => match parse_id(parts.next()) {
+                Some(id) => match cpu.watchpoints.delete(id) {
+                    Some(wp) => println!("Deleted watchpoint {wp}"),
+                    None => println!("No watchpoint #{id}"),
+                },
+                None => println!("A watchpoint id is expected"),
+            },
+            Some("bt") | Some("backtrace") => print!("{}", backtrace(&cpu, &symbols)),
+            Some("symbol") => match (parts.next().map(parse_addr), parts.next()) {
+                (Some(Some(addr)), Some(name)) => symbols.insert(addr, name),
+                (Some(Some(addr)), None) => {
+                    if symbols.remove(addr).is_none() {
+                        println!("No symbol at 0x{addr:04x}");
+                    }
+                }
+                _ => println!("An address is expected"),
+            },
+            Some("symbols") => {
+                if symbols.is_empty() {
+                    println!("No symbols");
+                }
+                for (addr, name) in symbols.iter() {
+                    println!("{addr:05} (0x{addr:04x}) {name}");
+                }
+            }
+            Some("save") => match parts.next() {
+                Some(fname) => match save_snapshot(&cpu, fname) {
+                    Ok(()) => println!("Snapshot saved to {fname}"),
+                    Err(e) => println!("Failed to save {fname}: {e}"),
+                },
+                None => println!("filename is missing"),
+            },
+            Some("load") => match parts.next() {
+                Some(fname) => match restore_snapshot(&mut cpu, fname) {
+                    Ok(()) => println!("Snapshot restored from {fname}"),
+                    Err(e) => println!("Failed to restore {fname}: {e}"),
+                },
+                None => println!("filename is missing"),
+            },
+            Some("output") => match (parts.next(), parts.next()) {
+                (None, _) => println!("Program output: {output_mode}"),
+                (Some("terminal"), _) => {
+                    cpu.set_output(Box::new(terminal.clone()));
+                    output_mode = String::from("terminal");
+                }
+                (Some(mode @ ("log" | "file")), Some(fname)) => match FileOutput::create(fname) {
+                    Ok(file) => {
+                        if mode == "log" {
+                            let tee = TeeOutput::new(Box::new(terminal.clone()), Box::new(file));
+                            cpu.set_output(Box::new(tee));
+                            output_mode = format!("terminal and {fname}");
+                        } else {
+                            cpu.set_output(Box::new(file));
+                            output_mode = fname.to_string();
+                        }
+                    }
+                    Err(e) => println!("Failed to create {fname}: {e}"),
+                },
+                (Some("capture"), _) => {
+                    cpu.set_output(Box::new(buffer.clone()));
+                    output_mode = String::from("captured, use 'output show' to print it");
+                }
+                (Some("show"), _) => {
+                    let text = buffer.take();
+                    println!("--- program output ---");
+                    print!("{text}");
+                    if !text.is_empty() && !text.ends_with('\n') {
+                        println!();
+                    }
+                    println!("--- end of program output ---");
+                }
+                _ => println!("Usage: output [terminal|log <file>|file <file>|capture|show]"),
+            },
+            Some("feed") => match parts.next() {
+                Some(fname) => match FileInput::open(fname) {
+                    Ok(input) => cpu.feed(Box::new(input)),
+                    Err(e) => println!("Failed to open {fname}: {e}"),
+                },
+                None => println!("filename is missing"),
+            },
+            Some("disassemble") => match parts.next() {
+                Some(fname) => match save_disassembly(&cpu, fname, args.raw) {
+                    Ok(()) => println!("Disassembly written to {fname}"),
+                    Err(e) => println!("Failed to write {fname}: {e}"),
+                },
+                None => {
+                    if let Err(e) = cpu.disassemble(&mut io::stdout(), args.raw) {
+                        println!("Failed to disassemble: {e}");
+                    }
+                }
+            },
+            Some("coverage") => match (parts.next(), parts.next()) {
+                (Some("on") | Some("reset"), _) => {
+                    cpu.coverage = Some(Coverage::new());
+                    println!("Coverage started");
+                }
+                (Some("off"), _) => cpu.coverage = None,
+                (arg, fname) => match (&cpu.coverage, arg, fname) {
+                    (None, _, _) => println!("Coverage is off, use 'coverage on'"),
+                    (Some(coverage), None, _) => print!("{}", coverage.summary(cpu.footprint)),
+                    (Some(_), Some("save"), Some(fname)) => match save_coverage(&cpu, fname) {
+                        Ok(()) => println!("Coverage saved to {fname}"),
+                        Err(e) => println!("Failed to save {fname}: {e}"),
+                    },
+                    _ => println!("Usage: coverage [on|off|reset|save <file>]"),
+                },
+            },
+            Some("strings") => match parts.next() {
+                Some("on") | Some("reset") => {
+                    cpu.strings = Some(StringCapture::new());
+                    println!("Capturing the printed strings");
+                }
+                Some("off") => cpu.strings = None,
+                Some("show") => match &cpu.strings {
+                    Some(capture) => {
+                        for s in capture.strings() {
+                            println!("{s}");
+                        }
+                    }
+                    None => println!("Capture is off, use 'strings on'"),
+                },
+                arg => match arg.map_or(Ok(STRINGS_MIN), str::parse::<usize>) {
+                    Ok(min) => {
+                        for s in cpu.strings(min.max(1)) {
+                            println!("{s}");
+                        }
+                    }
+                    Err(_) => println!("Usage: strings [<min>|on|off|reset|show]"),
+                },
+            },
+            Some("profile") => match parts.next() {
+                Some("on") | Some("reset") => {
+                    cpu.profiler = Some(Profiler::new());
+                    println!("Profiling started");
+                }
+                Some("off") => cpu.profiler = None,
+                arg => match (&cpu.profiler, arg.map(str::parse::<usize>)) {
+                    (None, _) => println!("Profiling is off, use 'profile on'"),
+                    (Some(_), Some(Err(_))) => println!("Usage: profile [on|off|reset|<count>]"),
+                    (Some(profiler), count) => {
+                        let limit = count.and_then(Result::ok).unwrap_or(PROFILE_LIMIT);
+                        print!("{}", profiler.report(&cpu, &symbols, limit));
+                    }
+                },
+            },
+            Some("trace") => match parts.next() {
+                None => match &cpu.tracer {
+                    Some(tracer) => println!(
+                        "Tracing in {:?} format, {} instruction(s) executed, ranges: {:?}",
+                        tracer.format(),
+                        tracer.steps(),
+                        tracer.ranges()
+                    ),
+                    None => println!("Not tracing"),
+                },
+                Some("off") => cpu.tracer = None,
+                Some(fname) => {
+                    let mut format = TraceFormat::Text;
+                    let mut ranges = vec![];
+                    let mut valid = true;
+                    for arg in parts {
+                        match arg {
+                            "text" => format = TraceFormat::Text,
+                            "json" => format = TraceFormat::Json,
+                            _ => match parse_range(arg) {
+                                Some(range) => ranges.push(range),
+                                None => {
+                                    println!("Invalid format or range {arg}");
+                                    valid = false;
+                                }
+                            },
+                        }
+                    }
+                    if valid {
+                        match File::create(fname) {
+                            Ok(f) => {
+                                let mut tracer = Tracer::new(Box::new(BufWriter::new(f)), format);
+                                for (start, end) in ranges {
+                                    tracer.add_range(start, end);
+                                }
+                                cpu.tracer = Some(tracer);
+                                println!("Tracing to {fname}");
+                            }
+                            Err(e) => println!("Failed to create {fname}: {e}"),
+                        }
+                    }
+                }
+            },
+            Some("record") => match (parts.next(), parts.next()) {
+                (None, _) if cpu.recording().is_some() => {
+                    println!("Already recording, use 'record stop' first")
+                }
+                (None, _) => {
+                    cpu.start_recording();
+                    println!("Recording started");
+                }
+                (Some("stop"), _) => match cpu.stop_recording() {
+                    Some(session) => {
+                        println!("Recording stopped, {} event(s) recorded", session.len());
+                        last_session = Some(session);
+                    }
+                    None => println!("Not recording"),
+                },
+                (Some("save"), Some(fname)) => match cpu.recording().or(last_session.as_ref()) {
+                    Some(session) => match session.save(fname) {
+                        Ok(()) => println!("{} event(s) saved to {fname}", session.len()),
+                        Err(e) => println!("Failed to save {fname}: {e}"),
+                    },
+                    None => println!("Nothing recorded"),
+                },
+                _ => println!("Usage: record [stop|save <file>]"),
+            },
+            Some("replay") => match parts.next() {
+                None => match cpu.replay_progress() {
+                    Some((pos, len)) => println!("Replaying event {pos} of {len}"),
+                    None => println!("Not replaying"),
+                },
+                Some("stop") => cpu.stop_replay(),
+                Some(fname) => match Session::load(fname) {
+                    Ok(session) => {
+                        println!("Replaying {} event(s) from {fname}", session.len());
+                        cpu.replay(session);
+                    }
+                    Err(e) => println!("Failed to load {fname}: {e}"),
+                },
+            },
+            Some("eval") => {
+                let src = parts.collect::<Vec<&str>>().join(" ");
+                match Expr::parse(&src).and_then(|e| e.eval(&cpu.env())) {
+                    Ok(v) => println!("{v} (0x{v:04x})"),
+                    Err(e) => println!("{e}"),
+                }
+            }
+            Some("c") | Some("continue") => report(cpu.cont(), &cpu, &symbols, &terminal),
             Some("p") | Some("print") => println!("{}", cpu.print()),
             Some("read") => {
                 if let Some(arg) = parts.next() {
-                    match arg.parse::<u16>() {
-                        Ok(n) => println!("[0x{n:04x}] => {} (0x{:04x})", cpu.read(n), cpu.read(n)),
-                        Err(_) => {
+                    match parse_addr(arg) {
+                        Some(n) => match cpu.read(n) {
+                            Ok(v) => println!("[0x{n:04x}] => {v} (0x{v:04x})"),
+                            Err(e) => println!("{e}"),
+                        },
+                        None => {
                             println!("Invalid memory address")
                         }
                     }
@@ -71,12 +508,183 @@ fn main() -> io::Result<()> {
                     println!("address is missing")
                 }
             }
-            Some("r") | Some("run") => cpu.run(args.verbose),
+            Some("r") | Some("run") => report(cpu.run(), &cpu, &symbols, &terminal),
             Some("q") | Some("quit") => break,
-            Some("s") | Some("step") => cpu.step(args.verbose),
+            Some("s") | Some("step") => match parts.next().map(str::parse::<u64>) {
+                None => report(cpu.step(), &cpu, &symbols, &terminal),
+                Some(Ok(n)) => report(cpu.cont_for(n), &cpu, &symbols, &terminal),
+                Some(Err(_)) => println!("Invalid number of steps"),
+            },
+            Some("rs") | Some("reverse-step") => {
+                report(cpu.reverse_step(), &cpu, &symbols, &terminal)
+            }
+            Some("rc") | Some("reverse-continue") => {
+                report(cpu.reverse_cont(), &cpu, &symbols, &terminal)
+            }
+            Some("history") => match parts.next().map(str::parse::<usize>) {
+                None => println!(
+                    "{} instruction(s) recorded, limit is {}",
+                    cpu.history.len(),
+                    cpu.history.limit()
+                ),
+                Some(Ok(limit)) => cpu.history.set_limit(limit),
+                Some(Err(_)) => println!("Invalid history limit"),
+            },
+            Some("n") | Some("next") => report(cpu.step_over(), &cpu, &symbols, &terminal),
+            Some("finish") => report(cpu.finish(), &cpu, &symbols, &terminal),
+            Some("until") => match parts.next().map(parse_addr) {
+                Some(Some(addr)) => report(cpu.until(addr), &cpu, &symbols, &terminal),
+                Some(None) => println!("Invalid memory address"),
+                None => println!("address is missing"),
+            },
             _ => println!("Unknown input"),
         }
     }
 
+    let session = cpu.recording().or(last_session.as_ref());
+    if let (Some(fname), Some(session)) = (&args.record, session) {
+        session.save(fname)?;
+        println!("Session saved to {fname}");
+    }
+
+    if let Some(fname) = &args.coverage {
+        save_coverage(&cpu, fname)?;
+        println!("Coverage saved to {fname}");
+    }
+
+    if let Some(fname) = &args.save {
+        save_snapshot(&cpu, fname)?;
+        println!("Snapshot saved to {fname}");
+    }
+
     Ok(())
 }
+
+fn save_snapshot(cpu: &Cpu, fname: &str) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(fname)?);
+    cpu.save(&mut out)?;
+    out.flush()
+}
+
+fn restore_snapshot(cpu: &mut Cpu, fname: &str) -> io::Result<()> {
+    let mut input = BufReader::new(File::open(fname)?);
+    cpu.restore(&mut input)
+}
+
+fn save_disassembly(cpu: &Cpu, fname: &str, raw: bool) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(fname)?);
+    cpu.disassemble(&mut out, raw)?;
+    out.flush()
+}
+
+// The summary is followed by the annotated disassembly of the program
+fn save_coverage(cpu: &Cpu, fname: &str) -> io::Result<()> {
+    let Some(coverage) = &cpu.coverage else {
+        return Ok(());
+    };
+    let mut out = BufWriter::new(File::create(fname)?);
+    write!(out, "{}", coverage.summary(cpu.footprint))?;
+    writeln!(out)?;
+    coverage.annotate(cpu, cpu.footprint, &mut out)?;
+    out.flush()
+}
+
+fn backtrace(cpu: &Cpu, symbols: &Symbols) -> String {
+    let sym = |addr| match symbols.get(addr) {
+        Some(name) => format!(" <{name}>"),
+        None => String::new(),
+    };
+
+    let mut out = format!("IP at {:05} (0x{:04x}){}\n", cpu.ip, cpu.ip, sym(cpu.ip));
+    for (idx, frame) in cpu.call_stack.frames().iter().rev().enumerate() {
+        out.push_str(&format!(
+            "#{idx:<3} call at {:05} (0x{:04x}) to 0x{:04x}{}, returns to 0x{:04x}\n",
+            frame.call_site,
+            frame.call_site,
+            frame.target,
+            sym(frame.target),
+            frame.ret
+        ));
+    }
+    if let Some(m) = &cpu.call_stack.last_mismatch {
+        out.push_str(&format!(
+            "warning: {} mismatched return(s), last one: {m}\n",
+            cpu.call_stack.mismatches
+        ));
+    }
+    out
+}
+
+fn set_breakpoint(cpu: &mut Cpu, addr: u16, name: Option<&str>, cond: Option<&str>) {
+    // Check the condition first to not leave an unconditional breakpoint behind
+    let cond = match cond.map(Expr::parse).transpose() {
+        Ok(cond) => cond,
+        Err(e) => {
+            println!("Invalid condition: {e}");
+            return;
+        }
+    };
+    match cpu.breakpoints.add(addr, name) {
+        Ok(id) => {
+            let bp = cpu.breakpoints.get_mut(id).expect("breakpoint just added");
+            bp.condition = cond;
+            println!("Breakpoint {bp}");
+        }
+        Err(e) => println!("Failed to set breakpoint: {e}"),
+    }
+}
+
+// Addresses are given in decimal or in hexadecimal with the 0x prefix
+fn parse_addr(arg: &str) -> Option<u16> {
+    match arg.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => arg.parse::<u16>().ok(),
+    }
+}
+
+// A target is a register (r0 to r7), an address or an inclusive range of
+// addresses (start..end)
+fn parse_target(arg: &str) -> Result<Target, String> {
+    if let Some(reg) = arg.strip_prefix('r') {
+        return match reg.parse::<u16>() {
+            Ok(r) if r < layout::NUM_REGS => {
+                Target::new(layout::REG_MIN + r, layout::REG_MIN + r).map_err(|e| e.to_string())
+            }
+            _ => Err(format!("unknown register {arg}")),
+        };
+    }
+    let (start, end) = match arg.split_once("..") {
+        Some((start, end)) => (start, end),
+        None => (arg, arg),
+    };
+    match (parse_addr(start), parse_addr(end)) {
+        (Some(start), Some(end)) => Target::new(start, end).map_err(|e| e.to_string()),
+        _ => Err(format!("invalid address {arg}")),
+    }
+}
+
+// A range of addresses is given as start..end, both included
+fn parse_range(arg: &str) -> Option<(u16, u16)> {
+    let (start, end) = arg.split_once("..")?;
+    let (start, end) = (parse_addr(start)?, parse_addr(end)?);
+    (start <= end).then_some((start, end))
+}
+
+fn parse_id(arg: Option<&str>) -> Option<usize> {
+    arg?.parse::<usize>().ok()
+}
+
+// Errors stop the CPU but the debugger keeps running so the state can be
+// inspected.
+fn report(reason: StopReason, cpu: &Cpu, symbols: &Symbols, terminal: &TerminalOutput) {
+    // Executing the requested instructions is the normal case
+    if reason != StopReason::StepLimit {
+        terminal.end_line();
+        println!("{reason}");
+    }
+    if reason == StopReason::Halted
+        && let Some(profiler) = &cpu.profiler
+    {
+        print!("{}", profiler.report(cpu, symbols, PROFILE_LIMIT));
+    }
+}